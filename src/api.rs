@@ -0,0 +1,532 @@
+use std::{collections::HashMap, future::Future, io, sync::Arc};
+
+use axum::{
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::Response,
+    routing::{get, post},
+    Json, Router,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    async_trait,
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{
+        channel::Message,
+        id::{GuildId, UserId},
+    },
+    prelude::{RwLock, TypeMapKey},
+};
+use songbird::{
+    tracks::{PlayMode, TrackQueue},
+    Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+use tokio::sync::broadcast;
+
+use crate::{
+    check_msg, history, permissions, player, radio,
+    session::{GuildSession, Refusal, Requester},
+    shards, storage,
+    web::{Reply, WebState},
+};
+
+/// Tokens belong to guilds, so each process keeps those of its own shards.
+fn tokens_file() -> String {
+    format!("api_tokens{}.json", shards::config().file_suffix())
+}
+
+/// How many events a slow WebSocket client may fall behind before missing some.
+const EVENT_BUFFER: usize = 64;
+
+/// A track event pushed to WebSocket clients of the guild.
+#[derive(Clone, Debug, Serialize)]
+pub struct PlayerEvent {
+    #[serde(skip)]
+    guild_id: GuildId,
+    event: &'static str,
+    title: Option<String>,
+    url: Option<String>,
+}
+
+/// The API token of a guild, which queues music on behalf of the manager who
+/// created it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Token {
+    token: String,
+    owner: u64,
+}
+
+/// Compares two tokens in time which only depends on their length, so
+/// response times do not give away how much of a guess was right.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// API tokens per guild and the stream of player events.
+pub struct Api {
+    tokens: RwLock<HashMap<u64, Token>>,
+    events: broadcast::Sender<PlayerEvent>,
+}
+
+impl TypeMapKey for Api {
+    type Value = Arc<Api>;
+}
+
+impl Api {
+    pub async fn load() -> Self {
+        Self {
+            tokens: RwLock::new(storage::load(&tokens_file()).await),
+            events: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+
+    async fn guild(&self, token: &str) -> Option<GuildId> {
+        let tokens = self.tokens.read().await;
+        // Every token is compared, so the time taken does not tell which matched.
+        let mut found = None;
+        for (guild_id, guild_token) in tokens.iter() {
+            if same_token(&guild_token.token, token) {
+                found = Some(GuildId(*guild_id));
+            }
+        }
+        found
+    }
+
+    /// Who the token of a guild queues music for, unless it was revoked.
+    async fn owner(&self, guild_id: GuildId) -> Option<UserId> {
+        let tokens = self.tokens.read().await;
+        tokens.get(&guild_id.0).map(|token| UserId(token.owner))
+    }
+
+    /// Replaces the token of a guild, invalidating the previous one.
+    async fn rotate(&self, guild_id: GuildId, owner: UserId) -> io::Result<String> {
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(40)
+            .map(char::from)
+            .collect();
+
+        let mut tokens = self.tokens.write().await;
+        tokens.insert(
+            guild_id.0,
+            Token {
+                token: token.clone(),
+                owner: owner.0,
+            },
+        );
+        storage::save(&tokens_file(), &*tokens).await?;
+        Ok(token)
+    }
+
+    async fn revoke(&self, guild_id: GuildId) -> io::Result<bool> {
+        let mut tokens = self.tokens.write().await;
+        let revoked = tokens.remove(&guild_id.0).is_some();
+        storage::save(&tokens_file(), &*tokens).await?;
+        Ok(revoked)
+    }
+
+    /// Finds the guild of a request, from a `Bearer` token or a `token` query
+    /// parameter for clients which cannot set headers.
+    async fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        query: &HashMap<String, String>,
+    ) -> Result<GuildId, StatusCode> {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| query.get("token").map(String::as_str))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        self.guild(token.trim())
+            .await
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
+
+pub async fn api(ctx: &Context) -> Arc<Api> {
+    let data = ctx.data.read().await;
+    data.get::<Api>()
+        .expect("Api placed in at initialisation.")
+        .clone()
+}
+
+pub fn register_events(api: Arc<Api>, guild_id: GuildId, handler: &mut Call) {
+    for (event, name) in [
+        (TrackEvent::Play, "play"),
+        (TrackEvent::Pause, "pause"),
+        (TrackEvent::End, "end"),
+    ] {
+        handler.add_global_event(
+            Event::Track(event),
+            EventPublisher {
+                api: api.clone(),
+                guild_id,
+                name,
+            },
+        );
+    }
+}
+
+struct EventPublisher {
+    api: Arc<Api>,
+    guild_id: GuildId,
+    name: &'static str,
+}
+
+#[async_trait]
+impl VoiceEventHandler for EventPublisher {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(tracks) = ctx {
+            for (_, track) in tracks.iter() {
+                let metadata = track.metadata();
+                // Sending only fails while nobody is subscribed.
+                let _ = self.api.events.send(PlayerEvent {
+                    guild_id: self.guild_id,
+                    event: self.name,
+                    title: metadata.title.clone(),
+                    url: metadata.source_url.clone(),
+                });
+            }
+        }
+
+        None
+    }
+}
+
+pub fn router() -> Router<WebState> {
+    Router::new()
+        .route("/api/queue", get(queue))
+        .route("/api/enqueue", post(enqueue))
+        .route("/api/skip", post(skip))
+        .route("/api/pause", post(pause))
+        .route("/api/resume", post(resume))
+        .route("/api/seek", post(seek))
+        .route("/api/events", get(events))
+}
+
+type ApiResult<T> = Result<Json<T>, (StatusCode, Json<Reply>)>;
+
+fn error(status: StatusCode, message: &str) -> (StatusCode, Json<Reply>) {
+    (
+        status,
+        Json(Reply {
+            message: message.to_string(),
+        }),
+    )
+}
+
+fn ok(message: impl Into<String>) -> ApiResult<Reply> {
+    Ok(Json(Reply {
+        message: message.into(),
+    }))
+}
+
+#[derive(Serialize)]
+struct QueuedTrack {
+    title: Option<String>,
+    url: Option<String>,
+    duration: Option<u64>,
+    requester: Option<String>,
+}
+
+#[derive(Serialize)]
+struct QueueView {
+    playing: bool,
+    position: Option<u64>,
+    tracks: Vec<QueuedTrack>,
+}
+
+async fn queue(
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+) -> ApiResult<QueueView> {
+    let guild_id = state
+        .api
+        .authenticate(&headers, &query)
+        .await
+        .map_err(|status| error(status, "Invalid token"))?;
+
+    let session = state.sessions.get(guild_id).await;
+    let queue = session
+        .queue()
+        .await
+        .ok_or_else(|| error(StatusCode::CONFLICT, "Not in a voice channel to play in"))?;
+
+    let tracks = queue.current_queue();
+    let mut view = QueueView {
+        playing: false,
+        position: None,
+        tracks: Vec::with_capacity(tracks.len()),
+    };
+    if let Some(current) = tracks.first() {
+        if let Ok(info) = current.get_info().await {
+            view.playing = info.playing == PlayMode::Play;
+            view.position = Some(info.position.as_secs());
+        }
+    }
+    for track in tracks {
+        let metadata = track.metadata();
+        view.tracks.push(QueuedTrack {
+            title: Some(radio::title(&track).await),
+            url: metadata.source_url.clone(),
+            duration: metadata.duration.map(|duration| duration.as_secs()),
+            requester: history::requester(&track)
+                .await
+                .map(|user_id| user_id.to_string()),
+        });
+    }
+
+    Ok(Json(view))
+}
+
+#[derive(Deserialize)]
+struct EnqueueRequest {
+    url: String,
+}
+
+/// Adds a URL to the queue like `play`, for the manager who created the token;
+/// the bot has to be in a voice channel already.
+async fn enqueue(
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+    Json(request): Json<EnqueueRequest>,
+) -> ApiResult<Reply> {
+    let guild_id = state
+        .api
+        .authenticate(&headers, &query)
+        .await
+        .map_err(|status| error(status, "Invalid token"))?;
+
+    if !request.url.starts_with("http") {
+        return Err(error(StatusCode::BAD_REQUEST, "Must provide a valid URL"));
+    }
+
+    let session = state.sessions.get(guild_id).await;
+    if session.queue().await.is_none() {
+        return Err(error(
+            StatusCode::CONFLICT,
+            "Not in a voice channel to play in",
+        ));
+    }
+
+    let source = state
+        .resolver
+        .resolve(request.url, guild_id)
+        .await
+        .map_err(|why| {
+            error(
+                StatusCode::BAD_REQUEST,
+                &format!("Err starting source: {:?}", why),
+            )
+        })?;
+
+    let owner = state
+        .api
+        .owner(guild_id)
+        .await
+        .ok_or_else(|| error(StatusCode::UNAUTHORIZED, "Token revoked"))?;
+    let requester = Requester::member((&state.cache, &*state.http), guild_id, owner).await;
+    let _guard = session.lock().await;
+    match (
+        session.queue().await,
+        session.enqueue(source, &requester).await,
+    ) {
+        (Some(queue), Ok(_)) => ok(format!("Added song to queue: position {}", queue.len() - 1)),
+        (_, Err(Refusal::NotConnected)) | (None, _) => Err(error(
+            StatusCode::CONFLICT,
+            "Not in a voice channel to play in",
+        )),
+        (_, Err(why)) => Err(error(StatusCode::FORBIDDEN, &why.to_string())),
+    }
+}
+
+/// Runs a queue operation for the guild of the token, holding the session lock.
+async fn control<F: Future<Output = String>>(
+    state: &WebState,
+    headers: &HeaderMap,
+    query: &HashMap<String, String>,
+    operation: impl FnOnce(Arc<GuildSession>, TrackQueue) -> F,
+) -> ApiResult<Reply> {
+    let guild_id = state
+        .api
+        .authenticate(headers, query)
+        .await
+        .map_err(|status| error(status, "Invalid token"))?;
+
+    let session = state.sessions.get(guild_id).await;
+    let _guard = session.lock().await;
+    match session.queue().await {
+        Some(queue) => ok(operation(session.clone(), queue).await),
+        None => Err(error(
+            StatusCode::CONFLICT,
+            "Not in a voice channel to play in",
+        )),
+    }
+}
+
+async fn skip(
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+) -> ApiResult<Reply> {
+    control(&state, &headers, &query, |session, _| async move {
+        player::skip(&session).await
+    })
+    .await
+}
+
+async fn pause(
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+) -> ApiResult<Reply> {
+    control(&state, &headers, &query, |session, queue| async move {
+        player::pause(&session, &queue).await.to_string()
+    })
+    .await
+}
+
+async fn resume(
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+) -> ApiResult<Reply> {
+    control(&state, &headers, &query, |session, queue| async move {
+        player::resume(&session, &queue).await.to_string()
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+struct SeekRequest {
+    seconds: u64,
+}
+
+async fn seek(
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+    Json(request): Json<SeekRequest>,
+) -> ApiResult<Reply> {
+    control(&state, &headers, &query, |_, queue| async move {
+        player::seek(&queue, request.seconds)
+    })
+    .await
+}
+
+async fn events(
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    let guild_id = state.api.authenticate(&headers, &query).await?;
+    let events = state.api.events.subscribe();
+
+    Ok(ws.on_upgrade(move |socket| stream(socket, events, guild_id)))
+}
+
+/// Forwards the player events of a guild to a WebSocket until either side closes.
+async fn stream(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<PlayerEvent>,
+    guild_id: GuildId,
+) {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.guild_id == guild_id => {
+                    let text = match serde_json::to_string(&event) {
+                        Ok(text) => text,
+                        Err(why) => {
+                            println!("Err serializing event: {:?}", why);
+                            continue;
+                        }
+                    };
+                    if socket.send(WsMessage::Text(text)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[command]
+#[only_in(guilds)]
+async fn apitoken(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+
+    if !permissions::require_manager(ctx, msg).await {
+        return Ok(());
+    }
+
+    let api = api(ctx).await;
+
+    if args.single::<String>().ok().as_deref() == Some("revoke") {
+        let reply = match api.revoke(guild_id).await {
+            Ok(true) => "API token revoked",
+            Ok(false) => "This server has no API token",
+            Err(why) => {
+                println!("Err saving API tokens: {:?}", why);
+                "Err saving API tokens"
+            }
+        };
+        check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+        return Ok(());
+    }
+
+    let token = match api.rotate(guild_id, msg.author.id).await {
+        Ok(token) => token,
+        Err(why) => {
+            println!("Err saving API tokens: {:?}", why);
+            check_msg(msg.channel_id.say(&ctx.http, "Err saving API tokens").await);
+
+            return Ok(());
+        }
+    };
+
+    let sent = msg
+        .author
+        .direct_message(&ctx, |m| {
+            m.content(format!(
+                "API token for this server, replacing any previous one:\n`{}`\n\
+                 Send it as `Authorization: Bearer <token>`.",
+                token
+            ))
+        })
+        .await;
+
+    match sent {
+        Ok(_) => check_msg(msg.reply(ctx, "Sent you a new API token").await),
+        Err(why) => {
+            println!("Err sending API token: {:?}", why);
+            check_msg(
+                msg.reply(ctx, "Could not DM you, check your privacy settings")
+                    .await,
+            );
+        }
+    }
+
+    Ok(())
+}
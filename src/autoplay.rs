@@ -0,0 +1,213 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use serenity::{
+    async_trait,
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    http::Http,
+    model::{channel::Message, id::ChannelId},
+};
+use songbird::{
+    tracks::{PlayMode, TrackHandle},
+    Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+
+use crate::{
+    check_msg, permissions,
+    session::{GuildSession, Requester},
+    settings::{self, Settings},
+    sources::Resolver,
+    stay::AlwaysOn,
+    ytdl,
+};
+
+/// How many recently played tracks autoplay avoids picking again.
+const RECENT_LEN: usize = 50;
+
+/// Registers the event which continues playback once the queue runs out.
+pub fn register_events(
+    ctx: &Context,
+    session: Arc<GuildSession>,
+    resolver: Arc<Resolver>,
+    settings: Arc<Settings>,
+    handler: &mut Call,
+) {
+    handler.add_global_event(
+        Event::Track(TrackEvent::End),
+        Autoplayer {
+            session,
+            resolver,
+            settings,
+            http: ctx.http.clone(),
+            picking: Arc::new(AtomicBool::new(false)),
+        },
+    );
+}
+
+struct Autoplayer {
+    session: Arc<GuildSession>,
+    resolver: Arc<Resolver>,
+    settings: Arc<Settings>,
+    http: Arc<Http>,
+    /// Set while a track is being picked, so a burst of ended tracks picks one.
+    picking: Arc<AtomicBool>,
+}
+
+/// Whether the queue holds nothing but the tracks which just ended.
+async fn ran_out(session: &GuildSession, ended: &[TrackHandle]) -> bool {
+    match session.tracks().await {
+        Some(tracks) => tracks
+            .iter()
+            .all(|track| ended.iter().any(|end| end.uuid() == track.uuid())),
+        None => false,
+    }
+}
+
+#[async_trait]
+impl VoiceEventHandler for Autoplayer {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let tracks = match ctx {
+            EventContext::Track(tracks) => tracks,
+            _ => return None,
+        };
+
+        let settings = self.settings.get(self.session.guild_id).await;
+        let channel_id = match (&settings.always_on, settings.autoplay) {
+            // The 24/7 playlist refills the queue instead, see `stay`.
+            (
+                Some(AlwaysOn {
+                    playlist: Some(_), ..
+                }),
+                _,
+            ) => return None,
+            (_, Some(channel_id)) => ChannelId(channel_id),
+            (Some(config), None) => ChannelId(config.text_channel),
+            (None, None) => return None,
+        };
+
+        // Only tracks which finished on their own seed autoplay; `stop` ends them too.
+        let seed = tracks
+            .iter()
+            .filter(|(state, _)| state.playing == PlayMode::End)
+            .filter_map(|(_, track)| track.metadata().source_url.clone())
+            .filter_map(|url| ytdl::video_id(&url))
+            .next_back()?;
+
+        let ended: Vec<_> = tracks.iter().map(|(_, track)| (*track).clone()).collect();
+        if !ran_out(&self.session, &ended).await || self.picking.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+
+        let recent: Vec<String> = self
+            .session
+            .state
+            .read()
+            .await
+            .history
+            .iter()
+            .rev()
+            .take(RECENT_LEN)
+            .filter_map(|entry| ytdl::video_id(&entry.url))
+            .collect();
+
+        // Picking runs yt-dlp twice, which would hold up the driver's other events.
+        let session = self.session.clone();
+        let resolver = self.resolver.clone();
+        let http = self.http.clone();
+        let picking = self.picking.clone();
+        tokio::spawn(async move {
+            pick(
+                &session, &resolver, &http, channel_id, &seed, &recent, &ended,
+            )
+            .await;
+            picking.store(false, Ordering::SeqCst);
+        });
+
+        None
+    }
+}
+
+/// Queues a track related to `seed`, unless something was queued meanwhile.
+async fn pick(
+    session: &GuildSession,
+    resolver: &Resolver,
+    http: &Http,
+    channel_id: ChannelId,
+    seed: &str,
+    recent: &[String],
+    ended: &[TrackHandle],
+) {
+    let url = match ytdl::related(seed, recent).await {
+        Some(url) => url,
+        None => return,
+    };
+    let source = match resolver.resolve(url, session.guild_id).await {
+        Ok(source) => source,
+        Err(why) => {
+            println!("Err starting autoplay source: {:?}", why);
+            return;
+        }
+    };
+
+    let _guard = session.lock().await;
+    if !ran_out(session, ended).await {
+        return;
+    }
+    let track = match session.enqueue(source, &Requester::Bot).await {
+        Ok(track) => track,
+        Err(why) => {
+            println!("Err queueing autoplay source: {}", why);
+            return;
+        }
+    };
+    let title = track
+        .metadata()
+        .title
+        .clone()
+        .unwrap_or(String::from("None"));
+    check_msg(
+        channel_id
+            .say(http, format!("Autoplay: **{}**", title))
+            .await,
+    );
+}
+
+#[command]
+#[aliases(ap)]
+#[only_in(guilds)]
+async fn autoplay(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    if !permissions::require_manager(ctx, msg).await {
+        return Ok(());
+    }
+
+    let enabled = settings::settings(ctx)
+        .await
+        .update(guild_id, |guild| {
+            if guild.autoplay.take().is_some() {
+                false
+            } else {
+                guild.autoplay = Some(msg.channel_id.0);
+                true
+            }
+        })
+        .await;
+
+    check_msg(
+        msg.channel_id
+            .say(
+                &ctx.http,
+                if enabled {
+                    "Autoplay enabled"
+                } else {
+                    "Autoplay disabled"
+                },
+            )
+            .await,
+    );
+
+    Ok(())
+}
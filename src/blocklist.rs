@@ -0,0 +1,517 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{
+        channel::Message,
+        id::{GuildId, RoleId, UserId},
+        Timestamp,
+    },
+    prelude::{RwLock, TypeMapKey},
+};
+use songbird::input::Metadata;
+
+use crate::{check_msg, permissions, shards, storage, ytdl};
+
+/// Each process keeps the blocklists of the guilds on its own shards.
+fn blocklists_file() -> String {
+    format!("blocklists{}.json", shards::config().file_suffix())
+}
+
+/// How many audit entries each guild keeps.
+const MAX_AUDIT: usize = 200;
+
+/// Audit entries shown per page.
+const PAGE_LEN: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Video,
+    Channel,
+    Keyword,
+    User,
+    Role,
+}
+
+impl Kind {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().trim_end_matches('s') {
+            "video" => Some(Kind::Video),
+            "channel" => Some(Kind::Channel),
+            "keyword" | "word" => Some(Kind::Keyword),
+            "user" => Some(Kind::User),
+            "role" => Some(Kind::Role),
+            _ => None,
+        }
+    }
+
+    /// Normalises a value as given in a command, accepting URLs for videos
+    /// and mentions for users and roles.
+    fn value(self, text: &str) -> Option<String> {
+        let text = text.trim();
+        match self {
+            Kind::Video => ytdl::video_id(text).or_else(|| {
+                (text.len() == 11
+                    && text
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+                .then(|| text.to_string())
+            }),
+            Kind::Channel | Kind::Keyword => (!text.is_empty()).then(|| text.to_lowercase()),
+            Kind::User | Kind::Role => text
+                .trim_start_matches("<@")
+                .trim_start_matches(['!', '&'])
+                .trim_end_matches('>')
+                .parse::<u64>()
+                .ok()
+                .map(|id| id.to_string()),
+        }
+    }
+
+    fn display(self, value: &str) -> String {
+        match self {
+            Kind::Video => format!("`{}`", value),
+            Kind::Channel | Kind::Keyword => format!("\"{}\"", value),
+            Kind::User => format!("<@{}>", value),
+            Kind::Role => format!("<@&{}>", value),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Video => "video",
+            Kind::Channel => "channel",
+            Kind::Keyword => "keyword",
+            Kind::User => "user",
+            Kind::Role => "role",
+        })
+    }
+}
+
+/// What happened to a blocklist, or what it stopped.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Added,
+    Removed,
+    Blocked,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct AuditEntry {
+    at: Timestamp,
+    /// The moderator who changed the list, or the user who was stopped;
+    /// `None` when the bot queued the track itself.
+    user: Option<u64>,
+    action: Action,
+    kind: Kind,
+    value: String,
+    /// The track which was stopped.
+    #[serde(default)]
+    track: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct GuildBlocklist {
+    videos: BTreeSet<String>,
+    channels: BTreeSet<String>,
+    keywords: BTreeSet<String>,
+    users: BTreeSet<String>,
+    roles: BTreeSet<String>,
+    audit: VecDeque<AuditEntry>,
+}
+
+impl GuildBlocklist {
+    fn entries(&self, kind: Kind) -> &BTreeSet<String> {
+        match kind {
+            Kind::Video => &self.videos,
+            Kind::Channel => &self.channels,
+            Kind::Keyword => &self.keywords,
+            Kind::User => &self.users,
+            Kind::Role => &self.roles,
+        }
+    }
+
+    fn entries_mut(&mut self, kind: Kind) -> &mut BTreeSet<String> {
+        match kind {
+            Kind::Video => &mut self.videos,
+            Kind::Channel => &mut self.channels,
+            Kind::Keyword => &mut self.keywords,
+            Kind::User => &mut self.users,
+            Kind::Role => &mut self.roles,
+        }
+    }
+
+    fn record(&mut self, entry: AuditEntry) {
+        self.audit.push_back(entry);
+        while self.audit.len() > MAX_AUDIT {
+            self.audit.pop_front();
+        }
+    }
+
+    /// The entry a track is blocked by, if any.
+    fn blocking(&self, metadata: &Metadata) -> Option<(Kind, String)> {
+        if let Some(video_id) = metadata.source_url.as_deref().and_then(ytdl::video_id) {
+            if self.videos.contains(&video_id) {
+                return Some((Kind::Video, video_id));
+            }
+        }
+        if let Some(channel) = &metadata.channel {
+            let channel = channel.to_lowercase();
+            if self.channels.contains(&channel) {
+                return Some((Kind::Channel, channel));
+            }
+        }
+        if let Some(title) = &metadata.title {
+            let title = title.to_lowercase();
+            if let Some(keyword) = self
+                .keywords
+                .iter()
+                .find(|keyword| title.contains(*keyword))
+            {
+                return Some((Kind::Keyword, keyword.clone()));
+            }
+        }
+        None
+    }
+}
+
+/// Content and people each guild has barred from the bot, with an audit
+/// trail of changes and of what was stopped.
+#[derive(Default)]
+pub struct Blocklists {
+    guilds: RwLock<HashMap<u64, GuildBlocklist>>,
+}
+
+impl TypeMapKey for Blocklists {
+    type Value = Arc<Blocklists>;
+}
+
+impl Blocklists {
+    pub async fn load() -> Self {
+        Self {
+            guilds: RwLock::new(storage::load(&blocklists_file()).await),
+        }
+    }
+
+    async fn get(&self, guild_id: GuildId) -> GuildBlocklist {
+        self.guilds
+            .read()
+            .await
+            .get(&guild_id.0)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Changes the blocklist of a guild and saves all of them.
+    async fn update<R>(&self, guild_id: GuildId, f: impl FnOnce(&mut GuildBlocklist) -> R) -> R {
+        let mut guilds = self.guilds.write().await;
+        let result = f(guilds.entry(guild_id.0).or_default());
+        if let Err(why) = storage::save(&blocklists_file(), &*guilds).await {
+            println!("Err saving blocklists: {:?}", why);
+        }
+        result
+    }
+
+    /// Checks a member against the guild's user and role blocklists,
+    /// recording it in the audit trail when they are blocked.
+    pub async fn check_member(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        roles: &[RoleId],
+    ) -> Result<(), String> {
+        let blocklist = self.get(guild_id).await;
+        let user = user_id.to_string();
+        let blocked = if blocklist.users.contains(&user) {
+            Some((Kind::User, user))
+        } else {
+            roles
+                .iter()
+                .map(|role| role.to_string())
+                .find(|role| blocklist.roles.contains(role))
+                .map(|role| (Kind::Role, role))
+        };
+        let (kind, value) = match blocked {
+            Some(blocked) => blocked,
+            None => return Ok(()),
+        };
+
+        self.update(guild_id, |blocklist| {
+            blocklist.record(AuditEntry {
+                at: Timestamp::now(),
+                user: Some(user_id.0),
+                action: Action::Blocked,
+                kind,
+                value,
+                track: None,
+            })
+        })
+        .await;
+        Err(String::from("You are not allowed to queue music here"))
+    }
+
+    /// Checks a resolved track against the guild's blocklist, recording it in
+    /// the audit trail when it is blocked. Returns why it was blocked.
+    pub async fn check_track(
+        &self,
+        guild_id: GuildId,
+        user_id: Option<UserId>,
+        metadata: &Metadata,
+    ) -> Result<(), String> {
+        let (kind, value) = match self.get(guild_id).await.blocking(metadata) {
+            Some(blocked) => blocked,
+            None => return Ok(()),
+        };
+
+        let title = metadata.title.clone().unwrap_or(String::from("None"));
+        let reason = format!(
+            "**{}** is blocked here by {} {}",
+            title,
+            kind,
+            kind.display(&value)
+        );
+        self.update(guild_id, |blocklist| {
+            blocklist.record(AuditEntry {
+                at: Timestamp::now(),
+                user: user_id.map(|user_id| user_id.0),
+                action: Action::Blocked,
+                kind,
+                value,
+                track: metadata.source_url.clone().or(Some(title)),
+            })
+        })
+        .await;
+        Err(reason)
+    }
+}
+
+async fn blocklists(ctx: &Context) -> Arc<Blocklists> {
+    let data = ctx.data.read().await;
+    data.get::<Blocklists>()
+        .expect("Blocklists placed in at initialisation.")
+        .clone()
+}
+
+/// Whether the message author may queue music, replying when they are blocked
+/// themselves or through one of their roles. Tracks are checked again when
+/// they are queued; this spares resolving them for blocked members.
+pub async fn allows_author(ctx: &Context, msg: &Message) -> bool {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return true,
+    };
+    let blocklists = blocklists(ctx).await;
+    let blocklist = blocklists.get(guild_id).await;
+    if blocklist.users.is_empty() && blocklist.roles.is_empty() {
+        return true;
+    }
+
+    let roles = match msg.member(ctx).await {
+        Ok(member) => member.roles,
+        Err(_) => Vec::new(),
+    };
+    match blocklists
+        .check_member(guild_id, msg.author.id, &roles)
+        .await
+    {
+        Ok(()) => true,
+        Err(why) => {
+            check_msg(msg.reply(ctx, why).await);
+            false
+        }
+    }
+}
+
+const USAGE: &str = "Usage: block <video|channel|keyword|user|role> <value>";
+
+/// Adds to or removes from a blocklist, for `block` and `unblock`.
+async fn change(ctx: &Context, msg: &Message, mut args: Args, add: bool) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    if !permissions::require_manager(ctx, msg).await {
+        return Ok(());
+    }
+
+    let kind = args
+        .single::<String>()
+        .ok()
+        .and_then(|kind| Kind::parse(&kind));
+    let entry = kind.and_then(|kind| kind.value(args.rest()).map(|value| (kind, value)));
+    let (kind, value) = match entry {
+        Some(entry) => entry,
+        None => {
+            let usage = if add {
+                USAGE.to_string()
+            } else {
+                USAGE.replacen("block", "unblock", 1)
+            };
+            check_msg(msg.channel_id.say(&ctx.http, usage).await);
+
+            return Ok(());
+        }
+    };
+
+    let changed = blocklists(ctx)
+        .await
+        .update(guild_id, |blocklist| {
+            let entries = blocklist.entries_mut(kind);
+            let changed = if add {
+                entries.insert(value.clone())
+            } else {
+                entries.remove(&value)
+            };
+            if changed {
+                blocklist.record(AuditEntry {
+                    at: Timestamp::now(),
+                    user: Some(msg.author.id.0),
+                    action: if add { Action::Added } else { Action::Removed },
+                    kind,
+                    value: value.clone(),
+                    track: None,
+                });
+            }
+            changed
+        })
+        .await;
+
+    let reply = match (add, changed) {
+        (true, true) => format!("Blocked {} {}", kind, kind.display(&value)),
+        (true, false) => format!("{} {} is already blocked", kind, kind.display(&value)),
+        (false, true) => format!("Unblocked {} {}", kind, kind.display(&value)),
+        (false, false) => format!("{} {} is not blocked", kind, kind.display(&value)),
+    };
+    check_msg(
+        msg.channel_id
+            .send_message(&ctx.http, |m| {
+                m.content(reply).allowed_mentions(|a| a.empty_parse())
+            })
+            .await,
+    );
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn block(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    change(ctx, msg, args, true).await
+}
+
+#[command]
+#[only_in(guilds)]
+async fn unblock(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    change(ctx, msg, args, false).await
+}
+
+#[command]
+#[aliases(blocked)]
+#[only_in(guilds)]
+async fn blocklist(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    if !permissions::require_manager(ctx, msg).await {
+        return Ok(());
+    }
+
+    let blocklist = blocklists(ctx).await.get(guild_id).await;
+    let kinds = [
+        Kind::Video,
+        Kind::Channel,
+        Kind::Keyword,
+        Kind::User,
+        Kind::Role,
+    ];
+    check_msg(
+        msg.channel_id
+            .send_message(&ctx.http, |m| {
+                m.embed(|e| {
+                    e.title("Blocklist");
+                    for kind in kinds {
+                        let entries: Vec<String> = blocklist
+                            .entries(kind)
+                            .iter()
+                            .map(|value| kind.display(value))
+                            .collect();
+                        let value = if entries.is_empty() {
+                            String::from("None")
+                        } else {
+                            entries.join(", ")
+                        };
+                        e.field(format!("{}s", kind), value, false);
+                    }
+                    e
+                })
+            })
+            .await,
+    );
+
+    Ok(())
+}
+
+#[command]
+#[aliases(blocklog)]
+#[only_in(guilds)]
+async fn blockaudit(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    if !permissions::require_manager(ctx, msg).await {
+        return Ok(());
+    }
+
+    let audit: Vec<AuditEntry> = blocklists(ctx)
+        .await
+        .get(guild_id)
+        .await
+        .audit
+        .into_iter()
+        .rev()
+        .collect();
+    if audit.is_empty() {
+        check_msg(msg.channel_id.say(&ctx.http, "Audit trail is empty").await);
+        return Ok(());
+    }
+
+    let pages = audit.len().div_ceil(PAGE_LEN);
+    let page = args.single::<usize>().unwrap_or(1).clamp(1, pages);
+
+    let mut audit_str = String::new();
+    for entry in audit.iter().skip((page - 1) * PAGE_LEN).take(PAGE_LEN) {
+        let what = format!("{} {}", entry.kind, entry.kind.display(&entry.value));
+        let who = match entry.user {
+            Some(user) => format!("<@{}>", user),
+            None => String::from("the bot"),
+        };
+        audit_str += &match entry.action {
+            Action::Added => format!("{} blocked {}", who, what),
+            Action::Removed => format!("{} unblocked {}", who, what),
+            Action::Blocked => match &entry.track {
+                Some(track) => format!("Stopped {} queueing {} ({})", who, track, what),
+                None => format!("Stopped {} ({})", who, what),
+            },
+        };
+        audit_str += &format!(" <t:{}:R>\n", entry.at.unix_timestamp());
+    }
+    audit_str = audit_str.replace("@everyone", "@\u{200B}everyone");
+    audit_str = audit_str.replace("@here", "@\u{200B}here");
+
+    msg.channel_id
+        .send_message(&ctx.http, |m| {
+            m.embed(|e| {
+                e.title("Blocklist audit trail");
+                e.description(&audit_str);
+                e.footer(|f| f.text(format!("page {}/{}", page, pages)));
+                e
+            })
+        })
+        .await?;
+
+    Ok(())
+}
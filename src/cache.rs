@@ -0,0 +1,382 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as SyncMutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serenity::{async_trait, prelude::Mutex};
+use songbird::{
+    input::Metadata, Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+use tokio::sync::Semaphore;
+
+use crate::{storage, ytdl};
+
+const INDEX_FILE: &str = "index.json";
+
+/// How many tracks may be downloaded into the cache at once.
+const MAX_CONCURRENT_DOWNLOADS: usize = 2;
+
+/// How long index changes are collected before they are written.
+const INDEX_SAVE_DELAY: Duration = Duration::from_secs(30);
+
+/// What the cache remembers about a downloaded track, so it can be played
+/// without asking yt-dlp again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CachedTrack {
+    size: u64,
+    /// Unix time of the last play, for LRU eviction.
+    last_used: u64,
+    source_url: String,
+    title: Option<String>,
+    artist: Option<String>,
+    track: Option<String>,
+    duration: Option<f64>,
+    thumbnail: Option<String>,
+}
+
+impl CachedTrack {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            source_url: Some(self.source_url.clone()),
+            title: self.title.clone(),
+            artist: self.artist.clone(),
+            track: self.track.clone(),
+            duration: self.duration.map(Duration::from_secs_f64),
+            thumbnail: self.thumbnail.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+/// A cached file handed out for playback, which is not evicted until every
+/// lease on it is dropped along with its track.
+pub struct Lease {
+    cache: Arc<TrackCache>,
+    video_id: String,
+    path: String,
+}
+
+impl Lease {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut leased = self.cache.leased.lock().unwrap();
+        if let Some(count) = leased.get_mut(&self.video_id) {
+            *count -= 1;
+            if *count == 0 {
+                leased.remove(&self.video_id);
+            }
+        }
+    }
+}
+
+/// On-disk audio cache keyed by YouTube video ID, bounded in size by evicting
+/// the least recently played tracks.
+pub struct TrackCache {
+    dir: PathBuf,
+    max_size: u64,
+    index: Mutex<HashMap<String, CachedTrack>>,
+    downloading: Mutex<HashSet<String>>,
+    /// How many leases each file has; a std mutex, as leases drop synchronously.
+    leased: SyncMutex<HashMap<String, usize>>,
+    /// Set while a save of the index is pending.
+    dirty: AtomicBool,
+    permits: Semaphore,
+}
+
+impl TrackCache {
+    /// Opens the cache if `CACHE_MAX_MB` is set, keeping it in `CACHE_DIR` or
+    /// the `cache` folder of the data directory.
+    pub async fn load() -> Option<Arc<Self>> {
+        let max_mb: u64 = match env::var("CACHE_MAX_MB").map(|mb| mb.parse()) {
+            Ok(Ok(max_mb)) if max_mb > 0 => max_mb,
+            Ok(_) => {
+                println!("Err parsing CACHE_MAX_MB, the track cache is disabled");
+                return None;
+            }
+            Err(_) => return None,
+        };
+        let dir = env::var("CACHE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| storage::data_dir().join("cache"));
+
+        if let Err(why) = tokio::fs::create_dir_all(&dir).await {
+            println!("Err creating cache dir {}: {:?}", dir.display(), why);
+            return None;
+        }
+
+        let index: HashMap<String, CachedTrack> = match tokio::fs::read(dir.join(INDEX_FILE)).await
+        {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|why| {
+                println!("Err parsing cache index, rebuilding it: {:?}", why);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        let cache = Arc::new(Self {
+            dir,
+            max_size: max_mb * 1024 * 1024,
+            index: Mutex::new(index),
+            downloading: Mutex::new(HashSet::new()),
+            leased: SyncMutex::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+            permits: Semaphore::new(MAX_CONCURRENT_DOWNLOADS),
+        });
+        cache.reconcile().await;
+        cache.evict(None).await;
+        cache.save_index().await;
+
+        Some(cache)
+    }
+
+    fn path(&self, video_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.audio", video_id))
+    }
+
+    /// Makes the index agree with the cache directory after a crash or a lost
+    /// index: downloads which never finished and files without an entry are
+    /// deleted, and entries whose file is gone are dropped.
+    async fn reconcile(&self) {
+        let mut index = self.index.lock().await;
+        let mut dir = match tokio::fs::read_dir(&self.dir).await {
+            Ok(dir) => dir,
+            Err(why) => {
+                println!("Err reading cache dir {}: {:?}", self.dir.display(), why);
+                return;
+            }
+        };
+
+        let mut found = HashSet::new();
+        while let Ok(Some(file)) = dir.next_entry().await {
+            let path = file.path();
+            let video_id = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(video_id) => video_id.to_string(),
+                None => continue,
+            };
+            let orphaned = match path.extension().and_then(|extension| extension.to_str()) {
+                Some("download") => true,
+                Some("audio") => match (index.get_mut(&video_id), file.metadata().await) {
+                    (Some(entry), Ok(metadata)) => {
+                        entry.size = metadata.len();
+                        found.insert(video_id);
+                        false
+                    }
+                    _ => true,
+                },
+                _ => false,
+            };
+            if orphaned {
+                if let Err(why) = tokio::fs::remove_file(&path).await {
+                    println!("Err deleting {}: {:?}", path.display(), why);
+                }
+            }
+        }
+        index.retain(|video_id, _| found.contains(video_id));
+    }
+
+    /// Writes the index next to the cached files, replacing it atomically.
+    async fn save_index(&self) {
+        let bytes = serde_json::to_vec(&*self.index.lock().await);
+        let tmp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        let result = match bytes {
+            Ok(bytes) => match tokio::fs::write(&tmp, bytes).await {
+                Ok(()) => tokio::fs::rename(&tmp, self.dir.join(INDEX_FILE)).await,
+                Err(why) => Err(why),
+            },
+            Err(why) => Err(why.into()),
+        };
+        if let Err(why) = result {
+            println!("Err saving cache index: {:?}", why);
+        }
+    }
+
+    /// Saves the index after a while, so a burst of changes is written once.
+    fn schedule_save(self: &Arc<Self>) {
+        if self.dirty.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let cache = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(INDEX_SAVE_DELAY).await;
+            cache.dirty.store(false, Ordering::SeqCst);
+            cache.save_index().await;
+        });
+    }
+
+    fn leased(&self, video_id: &str) -> bool {
+        self.leased.lock().unwrap().contains_key(video_id)
+    }
+
+    /// The file and metadata of a cached track, if the video has been downloaded before.
+    pub async fn get(self: &Arc<Self>, url: &str) -> Option<(Lease, Metadata)> {
+        let video_id = ytdl::video_id(url)?;
+        let path = self.path(&video_id);
+
+        let mut index = self.index.lock().await;
+        if tokio::fs::metadata(&path).await.is_err() {
+            if index.remove(&video_id).is_some() {
+                self.schedule_save();
+            }
+            return None;
+        }
+
+        let entry = index.get_mut(&video_id)?;
+        entry.last_used = now();
+        let metadata = entry.metadata();
+        // Taken under the index lock, so eviction cannot slip in between.
+        *self
+            .leased
+            .lock()
+            .unwrap()
+            .entry(video_id.clone())
+            .or_default() += 1;
+        self.schedule_save();
+
+        let lease = Lease {
+            cache: self.clone(),
+            video_id,
+            path: path.to_string_lossy().into_owned(),
+        };
+        Some((lease, metadata))
+    }
+
+    /// Downloads a track in the background unless it is cached or already downloading.
+    pub async fn populate(self: &Arc<Self>, metadata: &Metadata) {
+        let source_url = match &metadata.source_url {
+            Some(url) => url.clone(),
+            None => return,
+        };
+        let video_id = match ytdl::video_id(&source_url) {
+            Some(video_id) => video_id,
+            None => return,
+        };
+        if self.index.lock().await.contains_key(&video_id)
+            || !self.downloading.lock().await.insert(video_id.clone())
+        {
+            return;
+        }
+
+        let entry = CachedTrack {
+            size: 0,
+            last_used: now(),
+            source_url,
+            title: metadata.title.clone(),
+            artist: metadata.artist.clone(),
+            track: metadata.track.clone(),
+            duration: metadata.duration.map(|duration| duration.as_secs_f64()),
+            thumbnail: metadata.thumbnail.clone(),
+        };
+
+        let cache = self.clone();
+        tokio::spawn(async move {
+            cache.download(video_id.clone(), entry).await;
+            cache.downloading.lock().await.remove(&video_id);
+        });
+    }
+
+    async fn download(self: &Arc<Self>, video_id: String, mut entry: CachedTrack) {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("Cache semaphore is never closed.");
+
+        let partial = self.dir.join(format!("{}.download", video_id));
+        let output = tokio::process::Command::new("yt-dlp")
+            .args(["-f", "bestaudio/best", "--no-playlist", "--no-part", "-o"])
+            .arg(&partial)
+            .arg(&entry.source_url)
+            .output()
+            .await;
+
+        let succeeded = matches!(&output, Ok(output) if output.status.success());
+        if !succeeded {
+            println!("Err caching {}: {:?}", video_id, output.map(|o| o.status));
+            let _ = tokio::fs::remove_file(&partial).await;
+            return;
+        }
+
+        let path = self.path(&video_id);
+        if let Err(why) = tokio::fs::rename(&partial, &path).await {
+            println!("Err caching {}: {:?}", video_id, why);
+            return;
+        }
+        entry.size = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return,
+        };
+
+        self.index.lock().await.insert(video_id.clone(), entry);
+        self.evict(Some(&video_id)).await;
+    }
+
+    /// Deletes the least recently played tracks until the cache fits its size
+    /// cap, never deleting `keep` or files tracks are playing from.
+    async fn evict(self: &Arc<Self>, keep: Option<&str>) {
+        let mut index = self.index.lock().await;
+
+        let mut total: u64 = index.values().map(|entry| entry.size).sum();
+        let mut entries: Vec<(String, u64, u64)> = index
+            .iter()
+            .map(|(id, entry)| (id.clone(), entry.last_used, entry.size))
+            .collect();
+        entries.sort_by_key(|(_, last_used, _)| *last_used);
+
+        for (video_id, _, size) in entries {
+            if total <= self.max_size {
+                break;
+            }
+            if Some(video_id.as_str()) == keep || self.leased(&video_id) {
+                continue;
+            }
+            if let Err(why) = tokio::fs::remove_file(self.path(&video_id)).await {
+                println!("Err evicting {}: {:?}", video_id, why);
+            }
+            index.remove(&video_id);
+            total -= size;
+        }
+
+        self.schedule_save();
+    }
+}
+
+/// Registers the event which caches tracks as they start playing.
+pub fn register_events(cache: Arc<TrackCache>, handler: &mut Call) {
+    handler.add_global_event(Event::Track(TrackEvent::Play), CachePopulator { cache });
+}
+
+struct CachePopulator {
+    cache: Arc<TrackCache>,
+}
+
+#[async_trait]
+impl VoiceEventHandler for CachePopulator {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(tracks) = ctx {
+            for (_, track) in tracks.iter() {
+                self.cache.populate(track.metadata()).await;
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{COOKIE, SET_COOKIE},
+        HeaderMap, StatusCode,
+    },
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    cache::Cache,
+    client::Context,
+    framework::standard::{macros::command, CommandResult},
+    model::{
+        channel::Message,
+        id::{GuildId, UserId},
+    },
+    prelude::{Mutex, TypeMapKey},
+};
+use songbird::tracks::PlayMode;
+
+use crate::{
+    check_msg, history, player,
+    session::{self, Requester},
+    web,
+    web::{Reply, WebState},
+};
+
+const PAGE: &str = include_str!("dashboard.html");
+const COOKIE_NAME: &str = "breakcore_dashboard";
+
+/// How long a link sent by `!dashboard` can be opened.
+const LINK_TTL: Duration = Duration::from_secs(10 * 60);
+/// How long a browser stays logged in after opening a link.
+const LOGIN_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Clone, Copy)]
+struct Grant {
+    guild_id: GuildId,
+    user_id: UserId,
+    expires: Instant,
+}
+
+/// One-time dashboard links and the browser logins created from them.
+#[derive(Default)]
+pub struct Dashboard {
+    links: Mutex<HashMap<String, Grant>>,
+    logins: Mutex<HashMap<String, Grant>>,
+}
+
+impl TypeMapKey for Dashboard {
+    type Value = Arc<Dashboard>;
+}
+
+fn token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+impl Dashboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    async fn link(&self, guild_id: GuildId, user_id: UserId) -> String {
+        let now = Instant::now();
+        let mut links = self.links.lock().await;
+        links.retain(|_, grant| grant.expires > now);
+
+        let token = token();
+        links.insert(
+            token.clone(),
+            Grant {
+                guild_id,
+                user_id,
+                expires: now + LINK_TTL,
+            },
+        );
+        token
+    }
+
+    /// Invalidates a link, returning what it grants unless it expired.
+    async fn redeem(&self, link: &str) -> Option<Grant> {
+        let grant = self.links.lock().await.remove(link)?;
+        (grant.expires > Instant::now()).then_some(grant)
+    }
+
+    /// Creates a login token for a redeemed link.
+    async fn login(&self, grant: Grant) -> String {
+        let now = Instant::now();
+        let mut logins = self.logins.lock().await;
+        logins.retain(|_, grant| grant.expires > now);
+
+        let token = token();
+        logins.insert(
+            token.clone(),
+            Grant {
+                expires: now + LOGIN_TTL,
+                ..grant
+            },
+        );
+        token
+    }
+
+    async fn authenticate(&self, headers: &HeaderMap) -> Result<Grant, StatusCode> {
+        let token = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .find_map(|cookie| cookie.trim().strip_prefix(&format!("{}=", COOKIE_NAME)))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        match self.logins.lock().await.get(token) {
+            Some(grant) if grant.expires > Instant::now() => Ok(*grant),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+/// Public URL the dashboard is reachable at, configured through `DASHBOARD_URL`.
+/// The address the server binds to is no substitute: login links would leak
+/// over plain HTTP, or point at an address users cannot reach.
+fn base_url() -> Option<String> {
+    web::addr()?;
+    env::var("DASHBOARD_URL")
+        .ok()
+        .map(|url| url.trim_end_matches('/').to_string())
+}
+
+/// Warns when the HTTP server runs without a `DASHBOARD_URL`, which leaves
+/// `dashboard` unable to send links.
+pub fn check_config() {
+    if web::addr().is_some() && env::var("DASHBOARD_URL").is_err() {
+        println!("DASHBOARD_URL is not set, dashboard links are disabled");
+    }
+}
+
+pub fn router() -> Router<WebState> {
+    Router::new()
+        .route("/dashboard", get(page))
+        .route("/dashboard/login", get(login))
+        .route("/dashboard/api/state", get(state))
+        .route("/dashboard/api/:action", post(control))
+}
+
+async fn page() -> Html<&'static str> {
+    Html(PAGE)
+}
+
+async fn login(
+    State(state): State<WebState>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let grant = match query.get("token") {
+        Some(token) => state.dashboard.redeem(token).await,
+        None => None,
+    };
+    let grant = match grant {
+        Some(grant) => grant,
+        None => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                "This link expired or was already used, ask for a new one with !dashboard",
+            )
+                .into_response()
+        }
+    };
+
+    // Members blocked since asking for the link do not get to log in.
+    let requester =
+        Requester::member((&state.cache, &*state.http), grant.guild_id, grant.user_id).await;
+    let session = state.sessions.get(grant.guild_id).await;
+    if let Err(why) = session.check_member(&requester).await {
+        return (StatusCode::FORBIDDEN, why).into_response();
+    }
+
+    let token = state.dashboard.login(grant).await;
+    (
+        [(
+            SET_COOKIE,
+            format!(
+                "{}={}; Path=/dashboard; Max-Age={}; HttpOnly; SameSite=Strict",
+                COOKIE_NAME,
+                token,
+                LOGIN_TTL.as_secs()
+            ),
+        )],
+        Redirect::to("/dashboard"),
+    )
+        .into_response()
+}
+
+#[derive(Serialize)]
+struct TrackView {
+    title: String,
+    url: Option<String>,
+    duration: Option<u64>,
+    requester: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HistoryView {
+    title: String,
+    url: String,
+    requester: Option<String>,
+    played_at: i64,
+}
+
+#[derive(Serialize)]
+struct DashboardState {
+    guild: String,
+    connected: bool,
+    paused: bool,
+    position: Option<u64>,
+    queue: Vec<TrackView>,
+    history: Vec<HistoryView>,
+}
+
+fn user_name(cache: &Cache, user_id: UserId) -> String {
+    cache
+        .user(user_id)
+        .map(|user| user.name)
+        .unwrap_or_else(|| user_id.to_string())
+}
+
+async fn state(
+    State(state): State<WebState>,
+    headers: HeaderMap,
+) -> Result<Json<DashboardState>, StatusCode> {
+    let grant = state.dashboard.authenticate(&headers).await?;
+    let session = state.sessions.get(grant.guild_id).await;
+
+    let mut view = DashboardState {
+        guild: state
+            .cache
+            .guild_field(grant.guild_id, |guild| guild.name.clone())
+            .unwrap_or_else(|| grant.guild_id.to_string()),
+        connected: false,
+        paused: false,
+        position: None,
+        queue: Vec::new(),
+        history: Vec::new(),
+    };
+
+    if let Some(queue) = session.queue().await {
+        view.connected = true;
+
+        let tracks = queue.current_queue();
+        if let Some(current) = tracks.first() {
+            if let Ok(info) = current.get_info().await {
+                view.paused = info.playing == PlayMode::Pause;
+                view.position = Some(info.position.as_secs());
+            }
+        }
+
+        for track in tracks {
+            let metadata = track.metadata();
+            view.queue.push(TrackView {
+                title: metadata.title.clone().unwrap_or(String::from("None")),
+                url: metadata.source_url.clone(),
+                duration: metadata.duration.map(|duration| duration.as_secs()),
+                requester: history::requester(&track)
+                    .await
+                    .map(|user_id| user_name(&state.cache, user_id)),
+            });
+        }
+    }
+
+    view.history = session
+        .state
+        .read()
+        .await
+        .history
+        .iter()
+        .rev()
+        .map(|entry| HistoryView {
+            title: entry.title.clone(),
+            url: entry.url.clone(),
+            requester: entry
+                .requester
+                .map(|user_id| user_name(&state.cache, user_id)),
+            played_at: entry.played_at.unix_timestamp(),
+        })
+        .collect();
+
+    Ok(Json(view))
+}
+
+#[derive(Deserialize, Default)]
+struct Control {
+    index: Option<usize>,
+    to: Option<usize>,
+}
+
+/// Runs a queue control for the guild of the login, like the matching command would.
+async fn control(
+    State(state): State<WebState>,
+    headers: HeaderMap,
+    Path(action): Path<String>,
+    control: Option<Json<Control>>,
+) -> Result<Json<Reply>, StatusCode> {
+    let grant = state.dashboard.authenticate(&headers).await?;
+    let Json(control) = control.unwrap_or_default();
+
+    let requester =
+        Requester::member((&state.cache, &*state.http), grant.guild_id, grant.user_id).await;
+    let session = state.sessions.get(grant.guild_id).await;
+    match session.control(&requester).await {
+        Ok(()) => {}
+        Err(Some(message)) => return Ok(Json(Reply { message })),
+        Err(None) => return Err(StatusCode::TOO_MANY_REQUESTS),
+    }
+    let _guard = session.lock().await;
+
+    let queue = match session.queue().await {
+        Some(queue) => queue,
+        None => {
+            return Ok(Json(Reply {
+                message: String::from("Not in a voice channel to play in"),
+            }))
+        }
+    };
+
+    let message = match action.as_str() {
+        "skip" => player::skip(&session).await,
+        "pause" => player::pause(&session, &queue).await.to_string(),
+        "resume" => player::resume(&session, &queue).await.to_string(),
+        "remove" => player::remove(&queue, control.index.unwrap_or(0)).to_string(),
+        "move" => player::move_track(&queue, control.index.unwrap_or(0), control.to.unwrap_or(0))
+            .to_string(),
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+
+    println!(
+        "Dashboard {} by {} in {}: {}",
+        action, grant.user_id, grant.guild_id, message
+    );
+
+    Ok(Json(Reply { message }))
+}
+
+#[command]
+#[aliases(web)]
+#[only_in(guilds)]
+async fn dashboard(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+
+    let base_url = match base_url() {
+        Some(base_url) => base_url,
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "The dashboard is not enabled")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    let dashboard = {
+        let data = ctx.data.read().await;
+        data.get::<Dashboard>()
+            .expect("Dashboard placed in at initialisation.")
+            .clone()
+    };
+    let requester = Requester::author(ctx, msg).await;
+    if let Err(why) = session::get(ctx, guild_id)
+        .await
+        .check_member(&requester)
+        .await
+    {
+        check_msg(msg.reply(ctx, why).await);
+
+        return Ok(());
+    }
+    let token = dashboard.link(guild_id, msg.author.id).await;
+
+    let sent = msg
+        .author
+        .direct_message(&ctx, |m| {
+            m.content(format!(
+                "Dashboard for **{}**, valid once for {} minutes:\n{}/dashboard/login?token={}",
+                guild.name,
+                LINK_TTL.as_secs() / 60,
+                base_url,
+                token
+            ))
+        })
+        .await;
+
+    match sent {
+        Ok(_) => check_msg(msg.reply(ctx, "Sent you a dashboard link").await),
+        Err(why) => {
+            println!("Err sending dashboard link: {:?}", why);
+            check_msg(
+                msg.reply(ctx, "Could not DM you, check your privacy settings")
+                    .await,
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,289 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{channel::Message, id::GuildId},
+};
+use songbird::tracks::TrackHandle;
+
+use crate::{
+    check_msg, permissions,
+    session::{self, GuildSession},
+    settings, ytdl,
+};
+
+/// Query parameters which only track where a link was shared from.
+const TRACKING_PARAMS: [&str; 6] = ["utm_", "si=", "feature=", "fbclid=", "ref=", "pp="];
+
+/// What happens when a track which is already queued is added again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    Allow,
+    #[default]
+    Warn,
+    Reject,
+}
+
+impl Policy {
+    fn name(self) -> &'static str {
+        match self {
+            Policy::Allow => "allow",
+            Policy::Warn => "warn",
+            Policy::Reject => "reject",
+        }
+    }
+}
+
+/// Identifies a track regardless of how its URL was written: YouTube links by
+/// video ID, anything else by its URL without scheme, `www.`, fragment and
+/// tracking parameters.
+pub fn key(url: &str) -> String {
+    if let Some(video_id) = ytdl::video_id(url) {
+        return format!("youtube:{}", video_id);
+    }
+
+    let url = url.trim();
+    let url = url.split('#').next().unwrap_or_default();
+    let url = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .unwrap_or(url);
+    let url = url.strip_prefix("www.").unwrap_or(url);
+
+    let (address, query) = url.split_once('?').unwrap_or((url, ""));
+    let (host, path) = address.split_once('/').unwrap_or((address, ""));
+    let mut params: Vec<&str> = query
+        .split('&')
+        .filter(|param| {
+            !param.is_empty()
+                && !TRACKING_PARAMS
+                    .iter()
+                    .any(|prefix| param.starts_with(prefix))
+        })
+        .collect();
+    params.sort_unstable();
+
+    let mut key = format!("{}/{}", host.to_lowercase(), path.trim_end_matches('/'));
+    if !params.is_empty() {
+        key += "?";
+        key += &params.join("&");
+    }
+    key
+}
+
+fn track_key(track: &TrackHandle) -> Option<String> {
+    track.metadata().source_url.as_deref().map(key)
+}
+
+pub async fn policy(ctx: &Context, guild_id: GuildId) -> Policy {
+    settings::settings(ctx).await.get(guild_id).await.duplicates
+}
+
+/// Where a URL is queued already, `0` being the current track.
+pub fn position(tracks: &[TrackHandle], url: &str) -> Option<usize> {
+    let key = key(url);
+    tracks
+        .iter()
+        .position(|track| track_key(track).as_ref() == Some(&key))
+}
+
+/// Describes a queue position found by [`position`].
+pub fn place(position: usize) -> String {
+    match position {
+        0 => String::from("playing now"),
+        position => format!("at position **{}**", position),
+    }
+}
+
+/// The keys of every track in the queue, the current one included.
+async fn queued_keys(session: &GuildSession) -> HashSet<String> {
+    match session.queue().await {
+        Some(queue) => queue.current_queue().iter().filter_map(track_key).collect(),
+        None => HashSet::new(),
+    }
+}
+
+/// Applies the guild's policy to a track about to be added, replying when it
+/// is already queued. Returns whether the track may be added; the queue is
+/// checked again when it is added, as it may have changed while resolving.
+pub async fn check(ctx: &Context, msg: &Message, session: &GuildSession, url: &str) -> bool {
+    let policy = policy(ctx, session.guild_id).await;
+    if policy == Policy::Allow {
+        return true;
+    }
+
+    let position = match session.tracks().await {
+        Some(tracks) => position(&tracks, url),
+        None => None,
+    };
+    let place = match position {
+        Some(position) => place(position),
+        None => return true,
+    };
+    match policy {
+        Policy::Reject => {
+            check_msg(
+                msg.reply(ctx, format!("That song is already queued, {}", place))
+                    .await,
+            );
+            false
+        }
+        _ => {
+            check_msg(
+                msg.reply(
+                    ctx,
+                    format!("Heads up, that song is already queued, {}", place),
+                )
+                .await,
+            );
+            true
+        }
+    }
+}
+
+/// Applies the guild's policy to tracks about to be imported, counting those
+/// which are queued already or repeated in the import. Rejected duplicates are
+/// dropped and counted for the caller to report along with the ones refused
+/// when enqueueing; others are only warned about.
+pub async fn filter(
+    ctx: &Context,
+    msg: &Message,
+    session: &GuildSession,
+    urls: Vec<String>,
+) -> (Vec<String>, usize) {
+    let policy = policy(ctx, session.guild_id).await;
+    if policy == Policy::Allow {
+        return (urls, 0);
+    }
+
+    let mut seen = queued_keys(session).await;
+    let mut duplicates = 0;
+    let urls: Vec<String> = urls
+        .into_iter()
+        .filter(|url| {
+            let unique = seen.insert(key(url));
+            if !unique {
+                duplicates += 1;
+            }
+            unique || policy == Policy::Warn
+        })
+        .collect();
+
+    if policy == Policy::Reject {
+        return (urls, duplicates);
+    }
+    if duplicates > 0 {
+        check_msg(
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "Heads up, **{}** of these songs are already in the queue",
+                        duplicates
+                    ),
+                )
+                .await,
+        );
+    }
+    (urls, 0)
+}
+
+#[command]
+#[aliases(dupes, duplicate)]
+#[only_in(guilds)]
+async fn duplicates(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let settings = settings::settings(ctx).await;
+
+    let policy = match args.single::<String>().unwrap_or_default().as_str() {
+        "" => {
+            let policy = settings.get(guild_id).await.duplicates;
+            check_msg(
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        format!("Duplicate policy is **{}**", policy.name()),
+                    )
+                    .await,
+            );
+
+            return Ok(());
+        }
+        "allow" => Policy::Allow,
+        "warn" => Policy::Warn,
+        "reject" => Policy::Reject,
+        _ => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Usage: duplicates [allow|warn|reject]")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+    if !permissions::require_manager(ctx, msg).await {
+        return Ok(());
+    }
+
+    settings
+        .update(guild_id, |guild| guild.duplicates = policy)
+        .await;
+    check_msg(
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!("Duplicate policy set to **{}**", policy.name()),
+            )
+            .await,
+    );
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[bucket = "control"]
+async fn dedupe(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
+
+    let queue = match session.queue().await {
+        Some(queue) => queue,
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Not in a voice channel to play in")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    // The first of each song stays, so the current track is never removed.
+    let mut seen = HashSet::new();
+    let duplicates: Vec<usize> = queue
+        .current_queue()
+        .iter()
+        .enumerate()
+        .filter(|(_, track)| track_key(track).is_some_and(|key| !seen.insert(key)))
+        .map(|(index, _)| index)
+        .collect();
+    for index in duplicates.iter().rev() {
+        queue.dequeue(*index);
+    }
+
+    let reply = match duplicates.len() {
+        0 => String::from("No duplicates in the queue"),
+        1 => String::from("Removed **1** duplicate"),
+        count => format!("Removed **{}** duplicates", count),
+    };
+    check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+    Ok(())
+}
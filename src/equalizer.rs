@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use serenity::{
+    builder::CreateEmbed,
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::channel::Message,
+};
+
+use crate::{check_msg, permissions, session, settings};
+
+/// The bands of the equalizer and their centre frequencies in Hz.
+const BANDS: [(&str, u32); 5] = [
+    ("bass", 80),
+    ("low", 250),
+    ("mid", 1000),
+    ("high", 4000),
+    ("treble", 12000),
+];
+
+/// The largest boost or cut of a band, in dB.
+const MAX_GAIN: i8 = 12;
+
+const PRESETS: [(&str, [i8; 5]); 7] = [
+    ("flat", [0, 0, 0, 0, 0]),
+    ("rock", [4, 2, -2, 2, 4]),
+    ("pop", [-1, 2, 4, 2, -1]),
+    ("jazz", [3, 1, -1, 2, 3]),
+    ("classical", [3, 2, 0, 2, 3]),
+    ("bassboost", [8, 4, 0, 0, 0]),
+    ("vocal", [-2, -1, 4, 3, 0]),
+];
+
+/// Gains of the equalizer bands in dB, applied by ffmpeg when a track starts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Equalizer {
+    gains: [i8; 5],
+}
+
+impl Equalizer {
+    /// The ffmpeg audio filter for the equalizer, unless it is flat.
+    pub fn filter(&self) -> Option<String> {
+        let bands: Vec<String> = BANDS
+            .iter()
+            .zip(self.gains)
+            .filter(|(_, gain)| *gain != 0)
+            .map(|((_, frequency), gain)| format!("equalizer=f={}:t=o:w=2:g={}", frequency, gain))
+            .collect();
+
+        if bands.is_empty() {
+            None
+        } else {
+            Some(bands.join(","))
+        }
+    }
+
+    fn preset(&self) -> Option<&'static str> {
+        PRESETS
+            .iter()
+            .find(|(_, gains)| *gains == self.gains)
+            .map(|(name, _)| *name)
+    }
+}
+
+/// Draws a band as a bar growing left or right from the centre line.
+fn bar(gain: i8) -> String {
+    (-MAX_GAIN..=MAX_GAIN)
+        .map(|step| {
+            if step == 0 {
+                '┃'
+            } else if (gain > 0 && (1..=gain).contains(&step))
+                || (gain < 0 && (gain..0).contains(&step))
+            {
+                '█'
+            } else {
+                '·'
+            }
+        })
+        .collect()
+}
+
+fn eq_embed(eq: &Equalizer) -> CreateEmbed {
+    let chart: Vec<String> = BANDS
+        .iter()
+        .zip(eq.gains)
+        .map(|((name, _), gain)| format!("{:<6} {} {:+3} dB", name, bar(gain), gain))
+        .collect();
+
+    let mut e = CreateEmbed::default();
+    e.title("Equalizer");
+    e.description(format!("```\n{}\n```", chart.join("\n")));
+    e.footer(|f| f.text(format!("preset: {}", eq.preset().unwrap_or("custom"))));
+    e
+}
+
+fn usage() -> String {
+    let bands: Vec<&str> = BANDS.iter().map(|(name, _)| *name).collect();
+    let presets: Vec<&str> = PRESETS.iter().map(|(name, _)| *name).collect();
+    format!(
+        "Usage: eq <{}> <-{max}..{max}>, eq preset <{}>, eq reset",
+        bands.join("|"),
+        presets.join("|"),
+        max = MAX_GAIN
+    )
+}
+
+#[command]
+#[aliases(equalizer, equaliser)]
+#[only_in(guilds)]
+async fn eq(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let settings = settings::settings(ctx).await;
+
+    let mut eq = settings.get(guild_id).await.eq;
+    if !args.is_empty() {
+        if !permissions::require_manager(ctx, msg).await {
+            return Ok(());
+        }
+        let name = args.single::<String>()?.to_lowercase();
+        let changed = match name.as_str() {
+            "reset" => {
+                eq = Equalizer::default();
+                true
+            }
+            "preset" => match args.single::<String>().ok().and_then(|preset| {
+                PRESETS
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(&preset))
+            }) {
+                Some((_, gains)) => {
+                    eq.gains = *gains;
+                    true
+                }
+                None => false,
+            },
+            band => match (
+                BANDS.iter().position(|(name, _)| *name == band),
+                args.single::<i8>(),
+            ) {
+                (Some(index), Ok(gain)) if (-MAX_GAIN..=MAX_GAIN).contains(&gain) => {
+                    eq.gains[index] = gain;
+                    true
+                }
+                _ => false,
+            },
+        };
+
+        if !changed {
+            check_msg(msg.channel_id.say(&ctx.http, usage()).await);
+
+            return Ok(());
+        }
+
+        settings.update(guild_id, |guild| guild.eq = eq).await;
+
+        // Seeking restarts ffmpeg, which picks up the new filter.
+        let session = session::get(ctx, guild_id).await;
+        if let Some(current) = session.queue().await.and_then(|queue| queue.current()) {
+            if let Ok(info) = current.get_info().await {
+                let _ = current.seek_time(info.position);
+            }
+        }
+    }
+
+    check_msg(
+        msg.channel_id
+            .send_message(&ctx.http, |m| m.set_embed(eq_embed(&eq)))
+            .await,
+    );
+
+    Ok(())
+}
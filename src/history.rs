@@ -0,0 +1,305 @@
+use std::{sync::Arc, time::Duration};
+
+use serenity::{
+    async_trait,
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{channel::Message, id::UserId, Timestamp},
+    prelude::TypeMapKey,
+};
+use songbird::{
+    tracks::TrackHandle, Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+
+use crate::{
+    check_msg,
+    session::{self, GuildSession, Refusal},
+    sources,
+};
+
+/// How many played tracks are remembered per guild.
+const HISTORY_LEN: usize = 100;
+const PAGE_LEN: usize = 10;
+
+/// The user who queued a track, stored in the track's own type map.
+pub struct Requester;
+
+impl TypeMapKey for Requester {
+    type Value = UserId;
+}
+
+/// Marks a track queued by `previous`, which is in the history already.
+struct Rewound;
+
+impl TypeMapKey for Rewound {
+    type Value = ();
+}
+
+/// Marks the track `previous` skipped, whose end does not stop the walk back.
+struct Interrupted;
+
+impl TypeMapKey for Interrupted {
+    type Value = ();
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub url: String,
+    pub title: String,
+    pub requester: Option<UserId>,
+    pub played_at: Timestamp,
+}
+
+pub async fn set_requester(track: &TrackHandle, user_id: UserId) {
+    track.typemap().write().await.insert::<Requester>(user_id);
+}
+
+pub async fn requester(track: &TrackHandle) -> Option<UserId> {
+    track.typemap().read().await.get::<Requester>().copied()
+}
+
+/// Registers the event which records finished tracks into the guild history.
+pub fn register_events(session: Arc<GuildSession>, handler: &mut Call) {
+    handler.add_global_event(Event::Track(TrackEvent::End), HistoryRecorder { session });
+}
+
+struct HistoryRecorder {
+    session: Arc<GuildSession>,
+}
+
+#[async_trait]
+impl VoiceEventHandler for HistoryRecorder {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(tracks) = ctx {
+            for (state, track) in tracks.iter() {
+                // Clearing the queue also ends tracks which never got to play.
+                if state.play_time == Duration::ZERO {
+                    continue;
+                }
+                let (rewound, interrupted) = {
+                    let typemap = track.typemap().read().await;
+                    (
+                        typemap.contains_key::<Rewound>(),
+                        typemap.contains_key::<Interrupted>(),
+                    )
+                };
+                let metadata = track.metadata();
+                let url = match &metadata.source_url {
+                    Some(url) => url.clone(),
+                    None => continue,
+                };
+                let entry = HistoryEntry {
+                    url,
+                    title: metadata.title.clone().unwrap_or(String::from("None")),
+                    requester: requester(track).await,
+                    played_at: Timestamp::now(),
+                };
+
+                let mut state = self.session.state.write().await;
+                // A track which plays on after `previous` ends the walk back.
+                if !rewound && !interrupted {
+                    state.rewind = None;
+                }
+                if rewound {
+                    continue;
+                }
+                state.history.push_back(entry);
+                if state.history.len() > HISTORY_LEN {
+                    state.history.pop_front();
+                    state.rewind = state.rewind.and_then(|index| index.checked_sub(1));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[command]
+#[aliases(h)]
+#[only_in(guilds)]
+async fn history(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+
+    let session = session::get(ctx, guild_id).await;
+    let entries: Vec<HistoryEntry> = session
+        .state
+        .read()
+        .await
+        .history
+        .iter()
+        .rev()
+        .cloned()
+        .collect();
+
+    if entries.is_empty() {
+        check_msg(msg.channel_id.say(&ctx.http, "History is empty").await);
+        return Ok(());
+    }
+
+    let pages = entries.len().div_ceil(PAGE_LEN);
+    let page = args.single::<usize>().unwrap_or(1).clamp(1, pages);
+
+    let mut history_str = String::from("__**Recently played:**__\n");
+    for (index, entry) in entries
+        .iter()
+        .enumerate()
+        .skip((page - 1) * PAGE_LEN)
+        .take(PAGE_LEN)
+    {
+        history_str += &format!(
+            "`{}.` [{}]({}) <t:{}:R>",
+            index + 1,
+            entry.title,
+            entry.url,
+            entry.played_at.unix_timestamp()
+        );
+        if let Some(requester) = entry.requester {
+            history_str += &format!(" by <@{}>", requester);
+        }
+        history_str += "\n";
+    }
+    history_str = history_str.replace("@everyone", "@\u{200B}everyone");
+    history_str = history_str.replace("@here", "@\u{200B}here");
+
+    msg.channel_id
+        .send_message(&ctx.http, |m| {
+            m.embed(|e| {
+                e.description(&history_str);
+                e.footer(|f| f.text(format!("page {}/{}", page, pages)));
+                e
+            })
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[command]
+#[aliases(prev, back)]
+#[only_in(guilds)]
+#[bucket = "queue"]
+async fn previous(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+
+    let session = session::get(ctx, guild_id).await;
+    // Each use goes one track further back, until a track plays on its own.
+    let (index, entry) = {
+        let state = session.state.read().await;
+        let index = match state.rewind {
+            Some(index) => index.checked_sub(1),
+            None => state.history.len().checked_sub(1),
+        };
+        match index.and_then(|index| Some((index, state.history.get(index)?.clone()))) {
+            Some(found) => found,
+            None => {
+                check_msg(msg.channel_id.say(&ctx.http, "No previous track").await);
+                return Ok(());
+            }
+        }
+    };
+
+    if !session::join_author(ctx, msg, &session).await {
+        return Ok(());
+    }
+
+    let source = match sources::resolver(ctx)
+        .await
+        .resolve(entry.url, guild_id)
+        .await
+    {
+        Ok(source) => source,
+        Err(why) => {
+            println!("Err starting source: {:?}", why);
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, format!("Err starting source: {:?}", why))
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    let requester = session::Requester::author(ctx, msg).await;
+    let _guard = session.lock().await;
+    let (queue, track) = match (
+        session.queue().await,
+        session.enqueue(source, &requester).await,
+    ) {
+        (Some(queue), Ok(track)) => (queue, track),
+        (_, Err(Refusal::NotConnected)) | (None, _) => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Not in a voice channel to play in")
+                    .await,
+            );
+
+            return Ok(());
+        }
+        (_, Err(why)) => {
+            check_msg(msg.reply(ctx, why.to_string()).await);
+
+            return Ok(());
+        }
+    };
+
+    track.typemap().write().await.insert::<Rewound>(());
+    session.state.write().await.rewind = Some(index);
+
+    // Move the track right behind the current one and skip to it.
+    if queue.len() > 1 {
+        queue.modify_queue(|q| {
+            if let Some(previous) = q.pop_back() {
+                q.insert(1, previous);
+            }
+        });
+        if let Some(current) = queue.current() {
+            current.typemap().write().await.insert::<Interrupted>(());
+        }
+        session.skip().await;
+    }
+
+    check_msg(
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!("Playing previous track: **{}**", entry.title),
+            )
+            .await,
+    );
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn replay(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
+
+    if let Some(queue) = session.queue().await {
+        match queue.current() {
+            Some(current) => {
+                let _ = current.seek_time(Duration::ZERO);
+                check_msg(msg.channel_id.say(&ctx.http, "Song restarted").await);
+            }
+            None => {
+                check_msg(msg.channel_id.say(&ctx.http, "Nothing is playing").await);
+            }
+        }
+    } else {
+        check_msg(
+            msg.channel_id
+                .say(&ctx.http, "Not in a voice channel to play in")
+                .await,
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,466 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serenity::{
+    client::Context,
+    framework::standard::{
+        macros::{command, hook},
+        Args, CommandResult, StandardFramework,
+    },
+    model::{
+        channel::Message,
+        id::{GuildId, UserId},
+    },
+    prelude::{Mutex, TypeMapKey},
+};
+use songbird::tracks::TrackHandle;
+
+use crate::{
+    check_msg, history, permissions, settings,
+    sleep::{format_duration, parse_duration},
+    GENERAL_GROUP,
+};
+
+/// The longest delay or time span a guild may give a cooldown.
+const MAX_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+
+/// How many users' command uses are kept before old ones are dropped.
+const MAX_TRACKED: usize = 4096;
+
+/// How often one user may use the commands of a bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cooldown {
+    /// Seconds between two uses.
+    pub delay: u64,
+    /// Uses allowed within `time_span` seconds, `0` for no such limit.
+    pub limit: u32,
+    pub time_span: u64,
+}
+
+/// The buckets commands are limited by and their cooldowns, unless a guild
+/// changed them.
+const BUCKETS: [(&str, Cooldown); 3] = [
+    // Anything which adds to the queue.
+    (
+        "queue",
+        Cooldown {
+            delay: 3,
+            limit: 10,
+            time_span: 60,
+        },
+    ),
+    // Commands which query outside services.
+    (
+        "lookup",
+        Cooldown {
+            delay: 10,
+            limit: 0,
+            time_span: 0,
+        },
+    ),
+    // Playback controls, which are cheap but noisy when spammed.
+    (
+        "control",
+        Cooldown {
+            delay: 1,
+            limit: 15,
+            time_span: 30,
+        },
+    ),
+];
+
+/// Per-user queue limits of a guild and the command cooldowns there.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// How many tracks one user may have queued at once.
+    pub max_tracks: Option<usize>,
+    /// How many seconds of music one user may have queued at once.
+    pub max_duration: Option<u64>,
+    pub cooldowns: bool,
+    /// Cooldowns the guild changed, by bucket name.
+    pub buckets: BTreeMap<String, Cooldown>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_tracks: None,
+            max_duration: None,
+            cooldowns: true,
+            buckets: BTreeMap::new(),
+        }
+    }
+}
+
+impl Limits {
+    /// The cooldown of a bucket in this guild.
+    pub fn cooldown(&self, bucket: &str) -> Option<Cooldown> {
+        self.buckets.get(bucket).copied().or_else(|| {
+            BUCKETS
+                .iter()
+                .find(|(name, _)| *name == bucket)
+                .map(|(_, cooldown)| *cooldown)
+        })
+    }
+}
+
+/// When a user recently used the commands of a bucket.
+#[derive(Default)]
+struct Uses {
+    at: VecDeque<Instant>,
+    /// Whether the last use was refused, so only the first refusal is answered.
+    refused: bool,
+}
+
+/// Recent command uses per guild, user and bucket, which the guilds'
+/// cooldowns are checked against.
+#[derive(Default)]
+pub struct Cooldowns {
+    uses: Mutex<HashMap<(GuildId, UserId, &'static str), Uses>>,
+}
+
+impl TypeMapKey for Cooldowns {
+    type Value = Arc<Cooldowns>;
+}
+
+impl Cooldowns {
+    /// Records a use, or says how long until the next one is allowed and
+    /// whether the user was told already.
+    async fn take(
+        &self,
+        key: (GuildId, UserId, &'static str),
+        cooldown: Cooldown,
+    ) -> Result<(), (Duration, bool)> {
+        let now = Instant::now();
+        let delay = Duration::from_secs(cooldown.delay);
+        let time_span = Duration::from_secs(cooldown.time_span);
+
+        let mut all = self.uses.lock().await;
+        if all.len() >= MAX_TRACKED {
+            all.retain(|_, uses| {
+                uses.at
+                    .back()
+                    .is_some_and(|at| now.duration_since(*at) < MAX_COOLDOWN)
+            });
+        }
+        let uses = all.entry(key).or_default();
+        let window = delay.max(time_span);
+        while uses
+            .at
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= window)
+        {
+            uses.at.pop_front();
+        }
+
+        let mut wait = uses.at.back().map_or(Duration::ZERO, |last| {
+            (*last + delay).saturating_duration_since(now)
+        });
+        let limit = cooldown.limit as usize;
+        if limit > 0 {
+            let recent = uses
+                .at
+                .iter()
+                .filter(|at| now.duration_since(**at) < time_span)
+                .count();
+            if recent >= limit {
+                // The use which has to leave the time span before another fits.
+                let oldest = uses.at[uses.at.len() - limit];
+                wait = wait.max((oldest + time_span).saturating_duration_since(now));
+            }
+        }
+
+        if wait > Duration::ZERO {
+            let told = uses.refused;
+            uses.refused = true;
+            return Err((wait, told));
+        }
+        uses.at.push_back(now);
+        uses.refused = false;
+        Ok(())
+    }
+
+    /// Takes a use of a bucket for a user of a guild, unless the guild turned
+    /// cooldowns off. Fails with the time left until the next use, or `None`
+    /// when the user was told so already.
+    pub async fn take_bucket(
+        &self,
+        limits: &Limits,
+        guild_id: GuildId,
+        user_id: UserId,
+        bucket: &'static str,
+    ) -> Result<(), Option<Duration>> {
+        let cooldown = match limits.cooldown(bucket) {
+            Some(cooldown) if limits.cooldowns => cooldown,
+            _ => return Ok(()),
+        };
+        self.take((guild_id, user_id, bucket), cooldown)
+            .await
+            .map_err(|(wait, told)| (!told).then_some(wait))
+    }
+}
+
+pub async fn cooldowns(ctx: &Context) -> Arc<Cooldowns> {
+    let data = ctx.data.read().await;
+    data.get::<Cooldowns>()
+        .expect("Cooldowns placed in at initialisation.")
+        .clone()
+}
+
+/// Takes a use of a bucket for a user of a guild, see
+/// [`Cooldowns::take_bucket`].
+pub async fn take(
+    ctx: &Context,
+    guild_id: GuildId,
+    user_id: UserId,
+    bucket: &'static str,
+) -> Result<(), Option<Duration>> {
+    let limits = settings::settings(ctx).await.get(guild_id).await.limits;
+    cooldowns(ctx)
+        .await
+        .take_bucket(&limits, guild_id, user_id, bucket)
+        .await
+}
+
+/// Limits commands by the cooldowns of the bucket they are in.
+pub fn apply(framework: StandardFramework) -> StandardFramework {
+    framework.before(before)
+}
+
+/// The bucket a command is in, as set with `#[bucket]`.
+fn bucket(command_name: &str) -> Option<&'static str> {
+    GENERAL_GROUP
+        .options
+        .commands
+        .iter()
+        .find(|command| command.options.names.contains(&command_name))
+        .and_then(|command| command.options.bucket)
+}
+
+/// Cooldowns apply unless the guild turned them off, and never to managers.
+#[hook]
+async fn before(ctx: &Context, msg: &Message, command_name: &str) -> bool {
+    let (guild_id, bucket) = match (msg.guild_id, bucket(command_name)) {
+        (Some(guild_id), Some(bucket)) => (guild_id, bucket),
+        _ => return true,
+    };
+    match take(ctx, guild_id, msg.author.id, bucket).await {
+        Ok(()) => true,
+        Err(_) if permissions::is_manager(ctx, msg).await => true,
+        Err(wait) => {
+            // Only the first refused attempt is answered, so spamming stays quiet.
+            if let Some(wait) = wait {
+                check_msg(
+                    msg.reply(
+                        ctx,
+                        format!(
+                            "`{}` is on cooldown, try again in {}",
+                            command_name,
+                            format_duration(wait.max(Duration::from_secs(1)))
+                        ),
+                    )
+                    .await,
+                );
+            }
+            false
+        }
+    }
+}
+
+/// What a user may still add to the queue.
+pub struct Allowance {
+    tracks: Option<usize>,
+    duration: Option<Duration>,
+}
+
+impl Allowance {
+    /// Takes a track out of the allowance, or says why it does not fit.
+    /// Live streams have no length and only count as a track.
+    pub fn take(&mut self, duration: Option<Duration>) -> Result<(), String> {
+        if self.tracks == Some(0) {
+            return Err(String::from("You have queued as many tracks as allowed"));
+        }
+        if let (Some(left), Some(duration)) = (self.duration, duration) {
+            if duration > left {
+                return Err(format!(
+                    "That would take you over your queue limit, {} left",
+                    format_duration(left)
+                ));
+            }
+            self.duration = Some(left - duration);
+        }
+        if let Some(tracks) = &mut self.tracks {
+            *tracks -= 1;
+        }
+        Ok(())
+    }
+}
+
+/// What a user may still queue under the guild's limits, given the tracks in
+/// the queue.
+pub async fn allowance(limits: &Limits, tracks: &[TrackHandle], user_id: UserId) -> Allowance {
+    let mut queued = 0;
+    let mut duration = Duration::ZERO;
+    if limits.max_tracks.is_some() || limits.max_duration.is_some() {
+        for track in tracks {
+            if history::requester(track).await == Some(user_id) {
+                queued += 1;
+                duration += track.metadata().duration.unwrap_or_default();
+            }
+        }
+    }
+
+    Allowance {
+        tracks: limits.max_tracks.map(|max| max.saturating_sub(queued)),
+        duration: limits
+            .max_duration
+            .map(|max| Duration::from_secs(max).saturating_sub(duration)),
+    }
+}
+
+/// Parses a cooldown as given to `limits`: a delay like `5s`, and optionally
+/// how many uses fit into a time span, like `10/1m`.
+fn parse_cooldown(delay: &str, rate: Option<&str>) -> Option<Cooldown> {
+    let delay = parse_duration(delay)?;
+    let (limit, time_span) = match rate {
+        None | Some("off") => (0, Duration::ZERO),
+        Some(rate) => {
+            let (limit, time_span) = rate.split_once('/')?;
+            (limit.parse().ok()?, parse_duration(time_span)?)
+        }
+    };
+    if delay > MAX_COOLDOWN || time_span > MAX_COOLDOWN {
+        return None;
+    }
+    Some(Cooldown {
+        delay: delay.as_secs(),
+        limit,
+        time_span: time_span.as_secs(),
+    })
+}
+
+fn describe_cooldown(name: &str, cooldown: Cooldown) -> String {
+    let mut text = format!(
+        "{} {}",
+        name,
+        format_duration(Duration::from_secs(cooldown.delay))
+    );
+    if cooldown.limit > 0 {
+        text += &format!(
+            ", {} per {}",
+            cooldown.limit,
+            format_duration(Duration::from_secs(cooldown.time_span))
+        );
+    }
+    text
+}
+
+fn describe(limits: &Limits) -> String {
+    let cooldowns: Vec<String> = BUCKETS
+        .iter()
+        .filter_map(|(name, _)| Some(describe_cooldown(name, limits.cooldown(name)?)))
+        .collect();
+    format!(
+        "Per user: {} tracks, {} queued. Cooldowns are {}: {}",
+        limits
+            .max_tracks
+            .map(|max| max.to_string())
+            .unwrap_or_else(|| String::from("unlimited")),
+        limits
+            .max_duration
+            .map(|max| format_duration(Duration::from_secs(max)))
+            .unwrap_or_else(|| String::from("unlimited time")),
+        if limits.cooldowns { "on" } else { "off" },
+        cooldowns.join("; ")
+    )
+}
+
+#[command]
+#[only_in(guilds)]
+async fn limits(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let settings = settings::settings(ctx).await;
+
+    if args.is_empty() {
+        let limits = settings.get(guild_id).await.limits;
+        check_msg(msg.channel_id.say(&ctx.http, describe(&limits)).await);
+
+        return Ok(());
+    }
+    if !permissions::require_manager(ctx, msg).await {
+        return Ok(());
+    }
+
+    let mut limits = settings.get(guild_id).await.limits;
+    let name = args.single::<String>()?.to_lowercase();
+    let value = args.single::<String>().unwrap_or_default().to_lowercase();
+    let valid = match (name.as_str(), value.as_str()) {
+        ("tracks", "off") => {
+            limits.max_tracks = None;
+            true
+        }
+        ("tracks", value) => match value.parse::<usize>() {
+            Ok(max) if max > 0 => {
+                limits.max_tracks = Some(max);
+                true
+            }
+            _ => false,
+        },
+        ("duration", "off") => {
+            limits.max_duration = None;
+            true
+        }
+        ("duration", value) => match parse_duration(value) {
+            Some(max) => {
+                limits.max_duration = Some(max.as_secs());
+                true
+            }
+            None => false,
+        },
+        ("cooldowns", "on") | ("cooldowns", "off") => {
+            limits.cooldowns = value == "on";
+            true
+        }
+        (bucket, "reset") if limits.cooldown(bucket).is_some() => {
+            limits.buckets.remove(bucket);
+            true
+        }
+        (bucket, delay) if limits.cooldown(bucket).is_some() => {
+            let rate = args.single::<String>().ok();
+            match parse_cooldown(delay, rate.as_deref()) {
+                Some(cooldown) => {
+                    limits.buckets.insert(bucket.to_string(), cooldown);
+                    true
+                }
+                None => false,
+            }
+        }
+        _ => false,
+    };
+    if !valid {
+        check_msg(
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "Usage: limits tracks <n|off>, limits duration <1h30m|off>, limits cooldowns <on|off>, \
+                     limits <queue|lookup|control> <delay like 5s> [uses/time like 10/1m], \
+                     limits <queue|lookup|control> reset",
+                )
+                .await,
+        );
+
+        return Ok(());
+    }
+
+    settings
+        .update(guild_id, |guild| guild.limits = limits.clone())
+        .await;
+    check_msg(msg.channel_id.say(&ctx.http, describe(&limits)).await);
+
+    Ok(())
+}
@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    env,
+    io::{BufRead, BufReader},
+    process::ChildStderr,
+    sync::Arc,
+};
+
+use serenity::{
+    async_trait,
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{channel::Message, id::GuildId},
+    prelude::{RwLock, TypeMapKey},
+};
+use songbird::{
+    tracks::TrackHandle, Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+use tokio::runtime::Handle;
+
+use crate::{
+    check_msg, permissions, session,
+    settings::{self, Settings},
+    shards, storage, ytdl,
+};
+
+/// Every process writes its measurements to a file of its own, so shards
+/// sharing a data directory do not overwrite each other's.
+fn loudness_file() -> String {
+    format!("loudness{}.json", shards::config().file_suffix())
+}
+
+/// Gain is capped so quiet uploads are lifted without clipping.
+const MIN_GAIN: f32 = 0.1;
+const MAX_GAIN: f32 = 1.5;
+
+/// Integrated loudness tracks are normalised to, configured through
+/// `LOUDNESS_TARGET` in LUFS.
+fn target() -> f32 {
+    env::var("LOUDNESS_TARGET")
+        .ok()
+        .and_then(|target| target.parse().ok())
+        .unwrap_or(-14.0)
+}
+
+/// The gain applied to a track, stored with it so it is applied only once.
+struct Gain;
+
+impl TypeMapKey for Gain {
+    type Value = f32;
+}
+
+/// The loudness normalisation gain of a track, `1.0` when none was applied.
+pub async fn gain(track: &TrackHandle) -> f32 {
+    track
+        .typemap()
+        .read()
+        .await
+        .get::<Gain>()
+        .copied()
+        .unwrap_or(1.0)
+}
+
+/// Takes the normalisation gain off a track, restoring the volume it had before.
+pub async fn reset(track: &TrackHandle) {
+    let gain = match track.typemap().write().await.remove::<Gain>() {
+        Some(gain) => gain,
+        None => return,
+    };
+    if let Ok(info) = track.get_info().await {
+        let _ = track.set_volume(info.volume / gain);
+    }
+}
+
+/// Measured EBU R128 integrated loudness of tracks, keyed by YouTube video ID.
+pub struct Loudness {
+    target: f32,
+    levels: RwLock<HashMap<String, f32>>,
+}
+
+impl TypeMapKey for Loudness {
+    type Value = Arc<Loudness>;
+}
+
+impl Loudness {
+    pub async fn load() -> Self {
+        Self {
+            target: target(),
+            levels: RwLock::new(storage::load(&loudness_file()).await),
+        }
+    }
+
+    /// The volume factor bringing a measured track to the target loudness.
+    async fn gain_for(&self, video_id: &str) -> Option<f32> {
+        let level = *self.levels.read().await.get(video_id)?;
+        let gain = 10f32.powf((self.target - level) / 20.0);
+        Some(gain.clamp(MIN_GAIN, MAX_GAIN))
+    }
+
+    /// Applies the gain of a track which just started playing, unless it is
+    /// unmeasured or already normalised.
+    async fn apply(&self, track: &TrackHandle, volume: f32) {
+        let video_id = match track
+            .metadata()
+            .source_url
+            .as_deref()
+            .and_then(ytdl::video_id)
+        {
+            Some(video_id) => video_id,
+            None => return,
+        };
+        let gain = match self.gain_for(&video_id).await {
+            Some(gain) => gain,
+            None => return,
+        };
+
+        let mut typemap = track.typemap().write().await;
+        if typemap.contains_key::<Gain>() {
+            return;
+        }
+        typemap.insert::<Gain>(gain);
+        let _ = track.set_volume(volume * gain);
+    }
+
+    /// Whether a track was measured already.
+    pub async fn measured(&self, video_id: &str) -> bool {
+        self.levels.read().await.contains_key(video_id)
+    }
+
+    async fn record(&self, video_id: String, level: f32) {
+        let mut levels = self.levels.write().await;
+        levels.insert(video_id, level);
+        if let Err(why) = storage::save(&loudness_file(), &*levels).await {
+            println!("Err saving loudness: {:?}", why);
+        }
+    }
+
+    /// Records the integrated loudness the `ebur128` filter of a playing
+    /// ffmpeg reports, see [`FILTER`]. The filter only reports once the track
+    /// was decoded to its end, so skipped tracks stay unmeasured.
+    pub fn listen(self: &Arc<Self>, video_id: String, stderr: ChildStderr) {
+        let loudness = self.clone();
+        let runtime = Handle::current();
+        tokio::task::spawn_blocking(move || {
+            if let Some(level) = integrated(BufReader::new(stderr)) {
+                runtime.block_on(loudness.record(video_id, level));
+            }
+        });
+    }
+}
+
+/// The ffmpeg filter measuring the loudness of a track while it plays.
+pub const FILTER: &str = "ebur128=framelog=verbose";
+
+/// The integrated loudness from the summary `ebur128` logs when it ends,
+/// reading the whole log so ffmpeg never blocks on it.
+fn integrated(log: impl BufRead) -> Option<f32> {
+    let mut lines = log.lines().map_while(Result::ok);
+    lines.find(|line| line.trim() == "Integrated loudness:")?;
+    let level = lines
+        .next()
+        .and_then(|line| {
+            line.trim()
+                .strip_prefix("I:")?
+                .trim()
+                .strip_suffix("LUFS")?
+                .trim()
+                .parse::<f32>()
+                .ok()
+        })
+        .filter(|level| level.is_finite());
+    lines.for_each(drop);
+    level
+}
+
+pub async fn loudness(ctx: &Context) -> Arc<Loudness> {
+    let data = ctx.data.read().await;
+    data.get::<Loudness>()
+        .expect("Loudness placed in at initialisation.")
+        .clone()
+}
+
+/// Registers the event which normalises tracks as they start. Tracks are
+/// measured as they are decoded, see [`crate::sources`].
+pub fn register_events(
+    loudness: Arc<Loudness>,
+    settings: Arc<Settings>,
+    guild_id: GuildId,
+    handler: &mut Call,
+) {
+    handler.add_global_event(
+        Event::Track(TrackEvent::Play),
+        Normaliser {
+            loudness,
+            settings,
+            guild_id,
+        },
+    );
+}
+
+struct Normaliser {
+    loudness: Arc<Loudness>,
+    settings: Arc<Settings>,
+    guild_id: GuildId,
+}
+
+#[async_trait]
+impl VoiceEventHandler for Normaliser {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if !self.settings.get(self.guild_id).await.normalize {
+            return None;
+        }
+
+        if let EventContext::Track(tracks) = ctx {
+            for (state, track) in tracks.iter() {
+                self.loudness.apply(track, state.volume).await;
+            }
+        }
+
+        None
+    }
+}
+
+#[command]
+#[aliases(normalise)]
+#[only_in(guilds)]
+async fn normalize(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let settings = settings::settings(ctx).await;
+
+    let enable = match args.single::<String>().ok().as_deref() {
+        None => !settings.get(guild_id).await.normalize,
+        Some("on") => true,
+        Some("off") => false,
+        Some(_) => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Usage: normalize [on|off]")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+    if !permissions::require_manager(ctx, msg).await {
+        return Ok(());
+    }
+
+    settings
+        .update(guild_id, |guild| guild.normalize = enable)
+        .await;
+    if !enable {
+        for track in session::get(ctx, guild_id)
+            .await
+            .tracks()
+            .await
+            .unwrap_or_default()
+        {
+            reset(&track).await;
+        }
+    }
+
+    let reply = if enable {
+        "Loudness normalisation enabled, for tracks which played through once"
+    } else {
+        "Loudness normalisation disabled"
+    };
+    check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+    Ok(())
+}
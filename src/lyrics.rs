@@ -0,0 +1,317 @@
+use std::{env, time::Duration};
+
+use serde::Deserialize;
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::channel::Message,
+};
+use songbird::{input::Metadata, tracks::TrackHandle};
+
+use crate::{check_msg, net, session};
+
+/// Discord allows 4096 characters per embed description; keep pages readable.
+const PAGE_LEN: usize = 2000;
+
+/// How long a lyrics search may take.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest search response read.
+const MAX_RESPONSE_LEN: usize = 1024 * 1024;
+
+/// Words which only describe the upload and confuse lyrics searches.
+const NOISE: [&str; 10] = [
+    "official music video",
+    "official video",
+    "official audio",
+    "official lyric video",
+    "lyric video",
+    "lyrics",
+    "visualizer",
+    "audio",
+    "hd",
+    "4k",
+];
+
+/// Search endpoint of an LRCLIB compatible provider, configurable through
+/// `LYRICS_API_URL` so it can be pointed at a local mock.
+fn api_url() -> String {
+    env::var("LYRICS_API_URL").unwrap_or_else(|_| String::from("https://lrclib.net/api/search"))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LyricsResult {
+    track_name: Option<String>,
+    artist_name: Option<String>,
+    plain_lyrics: Option<String>,
+    synced_lyrics: Option<String>,
+}
+
+/// Turns an upload title like `Artist - Song (Official Video) [HD]` into a search query.
+pub fn clean_title(title: &str) -> String {
+    let mut cleaned = String::with_capacity(title.len());
+    let mut depth = 0usize;
+    for c in title.chars() {
+        match c {
+            '(' | '[' | '{' | '【' => depth += 1,
+            ')' | ']' | '}' | '】' => depth = depth.saturating_sub(1),
+            _ if depth == 0 => cleaned.push(c),
+            _ => {}
+        }
+    }
+
+    // Channels often append their name after a separator.
+    let cleaned = cleaned.split(['|', '/']).next().unwrap_or_default();
+    let lowercase = cleaned.to_ascii_lowercase();
+    let cleaned = match lowercase.find(" ft.").or_else(|| lowercase.find(" feat")) {
+        Some(index) => &cleaned[..index],
+        None => cleaned,
+    };
+
+    let mut words: Vec<&str> = cleaned.split_whitespace().collect();
+    for noise in NOISE {
+        let noise: Vec<&str> = noise.split(' ').collect();
+        let mut i = 0;
+        while i + noise.len() <= words.len() {
+            let matches = words[i..i + noise.len()]
+                .iter()
+                .zip(&noise)
+                .all(|(word, noise)| word.eq_ignore_ascii_case(noise));
+            if matches {
+                words.drain(i..i + noise.len());
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    words.join(" ")
+}
+
+/// Builds the search query for a track, preferring the music metadata yt-dlp found.
+pub fn query_for(metadata: &Metadata) -> Option<String> {
+    match (&metadata.artist, &metadata.track) {
+        (Some(artist), Some(track)) => Some(format!("{} {}", artist, track)),
+        _ => metadata.title.as_deref().map(clean_title),
+    }
+}
+
+async fn search(query: &str) -> Result<Option<LyricsResult>, String> {
+    let response = net::client()
+        .get(api_url())
+        .query(&[("q", query)])
+        .timeout(SEARCH_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|why| format!("{:?}", why))?;
+    let body = net::body(response, MAX_RESPONSE_LEN).await?;
+    let results: Vec<LyricsResult> =
+        serde_json::from_slice(&body).map_err(|why| format!("{:?}", why))?;
+
+    Ok(results
+        .into_iter()
+        .find(|result| result.plain_lyrics.is_some() || result.synced_lyrics.is_some()))
+}
+
+/// Splits lyrics into pages at line boundaries.
+fn paginate(lyrics: &str) -> Vec<String> {
+    let mut pages = vec![String::new()];
+    for line in lyrics.lines() {
+        let page = pages.last_mut().unwrap();
+        if !page.is_empty() && page.len() + line.len() + 1 > PAGE_LEN {
+            pages.push(String::new());
+        }
+        let page = pages.last_mut().unwrap();
+        page.push_str(line);
+        page.push('\n');
+    }
+    pages
+}
+
+/// Parses LRC lines like `[01:23.45]text` into timestamps and text.
+fn parse_lrc(lrc: &str) -> Vec<(Duration, String)> {
+    let mut lines = Vec::new();
+    for line in lrc.lines() {
+        let mut rest = line.trim();
+        let mut stamps = Vec::new();
+        while let Some(stripped) = rest.strip_prefix('[') {
+            let (stamp, after) = match stripped.split_once(']') {
+                Some(split) => split,
+                None => break,
+            };
+            let parsed = stamp.split_once(':').and_then(|(minutes, seconds)| {
+                Some(Duration::from_secs_f64(
+                    minutes.parse::<f64>().ok()? * 60.0 + seconds.parse::<f64>().ok()?,
+                ))
+            });
+            match parsed {
+                Some(at) => stamps.push(at),
+                // Metadata tags like `[ar:Artist]` carry no lyrics.
+                None => break,
+            }
+            rest = after;
+        }
+        for at in stamps {
+            lines.push((at, rest.trim().to_string()));
+        }
+    }
+    lines.sort_by_key(|(at, _)| *at);
+    lines
+}
+
+/// Posts synced lyrics line by line, following the position of `track`.
+async fn follow(ctx: Context, msg: Message, track: TrackHandle, lines: Vec<(Duration, String)>) {
+    for (at, line) in lines {
+        loop {
+            let position = match track.get_info().await {
+                Ok(info) => info.position,
+                // The track ended or was skipped.
+                Err(_) => return,
+            };
+            if position >= at {
+                break;
+            }
+            // Re-check regularly so pauses and seeks are followed.
+            tokio::time::sleep((at - position).min(Duration::from_secs(1))).await;
+        }
+
+        if !line.is_empty() {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, format!("🎤 {}", line).replace("@", "@\u{200B}"))
+                    .await,
+            );
+        }
+    }
+}
+
+#[command]
+#[aliases(ly)]
+#[only_in(guilds)]
+#[bucket = "lookup"]
+async fn lyrics(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+
+    let sync = args
+        .current()
+        .map(|arg| arg.eq_ignore_ascii_case("sync"))
+        .unwrap_or(false);
+    if sync {
+        args.advance();
+    }
+
+    let session = session::get(ctx, guild_id).await;
+    let current = match session.queue().await {
+        Some(queue) => queue.current(),
+        None => None,
+    };
+
+    let query = if args.is_empty() {
+        match current
+            .as_ref()
+            .and_then(|track| query_for(track.metadata()))
+        {
+            Some(query) => query,
+            None => {
+                check_msg(
+                    msg.channel_id
+                        .say(
+                            &ctx.http,
+                            "Nothing is playing, provide a song to search for",
+                        )
+                        .await,
+                );
+
+                return Ok(());
+            }
+        }
+    } else {
+        args.rest().to_string()
+    };
+
+    let result = match search(&query).await {
+        Ok(Some(result)) => result,
+        Ok(None) => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, format!("No lyrics found for **{}**", query))
+                    .await,
+            );
+
+            return Ok(());
+        }
+        Err(why) => {
+            println!("Err fetching lyrics: {:?}", why);
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Lyrics provider is unavailable")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    let title = match (&result.artist_name, &result.track_name) {
+        (Some(artist), Some(track)) => format!("{} - {}", artist, track),
+        _ => query.clone(),
+    };
+
+    if sync {
+        let synced = result.synced_lyrics.as_deref().map(parse_lrc);
+        match (current, synced) {
+            (Some(track), Some(lines)) if !lines.is_empty() => {
+                check_msg(
+                    msg.channel_id
+                        .say(&ctx.http, format!("Following lyrics for **{}**", title))
+                        .await,
+                );
+                tokio::spawn(follow(ctx.clone(), msg.clone(), track, lines));
+            }
+            (None, _) => {
+                check_msg(msg.channel_id.say(&ctx.http, "Nothing is playing").await);
+            }
+            _ => {
+                check_msg(
+                    msg.channel_id
+                        .say(&ctx.http, format!("No synced lyrics for **{}**", title))
+                        .await,
+                );
+            }
+        }
+
+        return Ok(());
+    }
+
+    let lyrics = match result.plain_lyrics {
+        Some(lyrics) => lyrics,
+        // Strip the timestamps if only synced lyrics exist.
+        None => parse_lrc(result.synced_lyrics.as_deref().unwrap_or_default())
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+
+    let pages = paginate(&lyrics);
+    let count = pages.len();
+    for (index, page) in pages.into_iter().enumerate() {
+        msg.channel_id
+            .send_message(&ctx.http, |m| {
+                m.embed(|e| {
+                    e.title(&title);
+                    e.description(page.replace("@", "@\u{200B}"));
+                    if count > 1 {
+                        e.footer(|f| f.text(format!("page {}/{}", index + 1, count)));
+                    }
+                    e
+                })
+            })
+            .await?;
+    }
+
+    Ok(())
+}
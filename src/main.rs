@@ -1,8 +1,40 @@
-use std::{env, time::Duration};
+mod api;
+mod autoplay;
+mod blocklist;
+mod cache;
+mod dashboard;
+mod duplicates;
+mod equalizer;
+mod history;
+mod limits;
+mod loudness;
+mod lyrics;
+mod metrics;
+mod net;
+mod permissions;
+mod player;
+mod playlists;
+mod podcasts;
+mod presence;
+mod radio;
+mod schedules;
+mod session;
+mod shards;
+mod shutdown;
+mod sleep;
+mod settings;
+mod sources;
+mod stay;
+mod storage;
+mod transitions;
+mod web;
+mod ytdl;
+
+use std::{env, sync::Arc, time::Duration};
 
 use serenity::{
     async_trait,
-    client::{Client, Context, EventHandler},
+    client::{bridge::gateway::event::ShardStageUpdateEvent, Client, Context, EventHandler},
     framework::{
         standard::{
             macros::{command, group},
@@ -10,25 +42,73 @@ use serenity::{
         },
         StandardFramework,
     },
-    model::{channel::Message, gateway::Ready, user::User},
+    model::{application::interaction::Interaction, channel::Message, gateway::Ready},
     prelude::GatewayIntents,
     Result as SerenityResult,
 };
 
-use songbird::{input::restartable::Restartable, SerenityInit, id::UserId};
+use songbird::{SerenityInit, SongbirdKey};
+
+use api::{Api, APITOKEN_COMMAND};
+use autoplay::AUTOPLAY_COMMAND;
+use blocklist::{Blocklists, BLOCKAUDIT_COMMAND, BLOCKLIST_COMMAND, BLOCK_COMMAND, UNBLOCK_COMMAND};
+use cache::TrackCache;
+use dashboard::{Dashboard, DASHBOARD_COMMAND};
+use duplicates::{DEDUPE_COMMAND, DUPLICATES_COMMAND};
+use equalizer::EQ_COMMAND;
+use history::{HISTORY_COMMAND, PREVIOUS_COMMAND, REPLAY_COMMAND};
+use limits::{Cooldowns, LIMITS_COMMAND};
+use loudness::{Loudness, NORMALIZE_COMMAND};
+use lyrics::LYRICS_COMMAND;
+use player::PLAYER_COMMAND;
+use playlists::{
+    PlaylistStore, SavedPlaylists, LOAD_COMMAND, PLADD_COMMAND, PLAYLISTS_COMMAND,
+    PLDELETE_COMMAND, PLEXPORT_COMMAND, PLIMPORT_COMMAND, PLREMOVE_COMMAND, SAVE_COMMAND,
+};
+use podcasts::{Podcasts, PODCAST_COMMAND};
+use presence::Presence;
+use radio::{Stations, RADIO_COMMAND};
+use schedules::{Schedules, LIST_SCHEDULES_COMMAND, SCHEDULE_COMMAND, UNSCHEDULE_COMMAND};
+use session::{Refusal, Requester, Sessions};
+use settings::Settings;
+use shards::{ShardManagerContainer, SHARDINFO_COMMAND};
+use shutdown::Snapshots;
+use sleep::SLEEP_COMMAND;
+use sources::Resolver;
+use stay::TWENTY_FOUR_SEVEN_COMMAND;
+use transitions::{CROSSFADE_COMMAND, PREBUFFER_COMMAND};
+use web::WebState;
 
 struct Handler;
 
 #[async_trait]
 impl EventHandler for Handler {
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         println!("{} is connected!", ready.user.name);
+
+        presence::presence(&ctx).await.update().await;
+        shutdown::restore(&ctx, &ready).await;
+        stay::restore(&ctx, &ready).await;
+        schedules::schedules(&ctx).await.start(&ctx);
+    }
+
+    async fn shard_stage_update(&self, _: Context, event: ShardStageUpdateEvent) {
+        shards::log_stage(&event);
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        player::handle_interaction(&ctx, &interaction).await;
     }
 }
 
 #[group]
 #[commands(
-    deafen, join, leave, play, skip, stop, pause, resume, undeafen, queue, remove, seek, playlist, spotifyplaylist
+    deafen, join, leave, play, skip, stop, pause, resume, undeafen, queue, remove, move_track, seek, playlist, spotifyplaylist,
+    player, history, previous, replay, save, load, playlists, pladd, plremove, pldelete, plexport,
+    plimport, autoplay, lyrics, shardinfo, dashboard, apitoken, crossfade, prebuffer,
+    normalize, eq, sleep, schedule, list_schedules, unschedule,
+    twenty_four_seven, radio, podcast, limits, duplicates, dedupe, block, unblock, blocklist,
+    blockaudit
 )]
 struct General;
 
@@ -38,29 +118,107 @@ async fn main() {
 
     let framework = if cfg!(debug_assertions) {
         println!("debug");
-        StandardFramework::new()
-            .configure(|c| c.prefix("~"))
-            .group(&GENERAL_GROUP)
+        limits::apply(
+            StandardFramework::new()
+                .configure(|c| c.prefix("~"))
+                .after(metrics::after)
+                .group(&GENERAL_GROUP),
+        )
     } else {
         println!("release");
-        StandardFramework::new()
-            .configure(|c| c.prefix("!"))
-            .group(&GENERAL_GROUP)
+        limits::apply(
+            StandardFramework::new()
+                .configure(|c| c.prefix("!"))
+                .after(metrics::after)
+                .group(&GENERAL_GROUP),
+        )
     };
     // Configure the client with your Discord bot token in the environment.
     let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
 
     let intents = GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT;
 
+    shards::check_data_files();
+    let settings = Arc::new(Settings::load().await);
+    let blocklists = Arc::new(Blocklists::load().await);
+    let loudness = Arc::new(Loudness::load().await);
+    let resolver = Arc::new(Resolver::new(
+        TrackCache::load().await,
+        settings.clone(),
+        loudness.clone(),
+    ));
+    let cooldowns = Arc::new(Cooldowns::default());
+    let sessions = Arc::new(Sessions::new(
+        settings.clone(),
+        blocklists.clone(),
+        cooldowns.clone(),
+        resolver.clone(),
+    ));
+    let api = Arc::new(Api::load().await);
+
     let mut client = Client::builder(&token, intents)
         .event_handler(Handler)
         .framework(framework)
         .register_songbird()
+        .type_map_insert::<Sessions>(sessions.clone())
+        .type_map_insert::<Resolver>(resolver.clone())
+        .type_map_insert::<Api>(api.clone())
+        .type_map_insert::<SavedPlaylists>(PlaylistStore::load().await)
+        .type_map_insert::<Snapshots>(shutdown::load().await)
+        .type_map_insert::<Settings>(settings)
+        .type_map_insert::<Loudness>(loudness)
+        .type_map_insert::<Schedules>(Arc::new(Schedules::load().await))
+        .type_map_insert::<Stations>(Arc::new(Stations::load().await))
+        .type_map_insert::<Podcasts>(Arc::new(Podcasts::load().await))
+        .type_map_insert::<Blocklists>(blocklists)
+        .type_map_insert::<Cooldowns>(cooldowns)
         .await
         .expect("Err creating client");
 
-    let _ = client
-        .start()
+    let presence = Arc::new(Presence::new(client.shard_manager.clone(), sessions.clone()));
+    tokio::spawn(presence.clone().rotate());
+
+    let dashboard = Arc::new(Dashboard::new());
+
+    {
+        let mut data = client.data.write().await;
+        data.insert::<ShardManagerContainer>(client.shard_manager.clone());
+        data.insert::<Presence>(presence);
+        data.insert::<Dashboard>(dashboard.clone());
+    }
+
+    if let Some(addr) = web::addr() {
+        dashboard::check_config();
+        let state = WebState {
+            cache: client.cache_and_http.cache.clone(),
+            http: client.cache_and_http.http.clone(),
+            sessions: sessions.clone(),
+            shard_manager: client.shard_manager.clone(),
+            dashboard,
+            api,
+            resolver,
+        };
+        tokio::spawn(web::serve(addr, state));
+    }
+
+    let manager = client
+        .data
+        .read()
+        .await
+        .get::<SongbirdKey>()
+        .cloned()
+        .expect("Songbird Voice client placed in at initialisation.");
+    let http = client.cache_and_http.http.clone();
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        println!("Shutting down");
+        shutdown::shutdown(&http, &manager, &sessions).await;
+        shard_manager.lock().await.shutdown_all().await;
+    });
+
+    let _ = shards::config()
+        .start(&mut client)
         .await
         .map_err(|why| println!("Client ended: {:?}", why));
 }
@@ -70,12 +228,10 @@ async fn deafen(ctx: &Context, msg: &Message) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
 
-    let handler_lock = match manager.get(guild_id) {
+    let handler_lock = match session.handler().await {
         Some(handler) => handler,
         None => {
             check_msg(msg.reply(ctx, "Not in a voice channel").await);
@@ -110,35 +266,9 @@ async fn join(ctx: &Context, msg: &Message) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let channel_id = guild
-        .voice_states
-        .get(&msg.author.id)
-        .and_then(|voice_state| voice_state.channel_id);
-
-    let connect_to = match channel_id {
-        Some(channel) => channel,
-        None => {
-            check_msg(msg.reply(ctx, "Not in a voice channel").await);
-
-            return Ok(());
-        }
-    };
-
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    let (_handle_lock, success) = manager.join(guild_id, connect_to).await;
-
-    if let Ok(_channel) = success {
-    } else {
-        check_msg(
-            msg.channel_id
-                .say(&ctx.http, "Error joining the channel")
-                .await,
-        );
-    }
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
+    session::join_author(ctx, msg, &session).await;
 
     Ok(())
 }
@@ -150,14 +280,12 @@ async fn leave(ctx: &Context, msg: &Message) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-    let has_handler = manager.get(guild_id).is_some();
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
+    let has_handler = session.handler().await.is_some();
 
     if has_handler {
-        if let Err(e) = manager.remove(guild_id).await {
+        if let Err(e) = session.disconnect(ctx).await {
             check_msg(
                 msg.channel_id
                     .say(&ctx.http, format!("Failed: {:?}", e))
@@ -176,9 +304,21 @@ async fn leave(ctx: &Context, msg: &Message) -> CommandResult {
 #[command]
 #[aliases(p)]
 #[only_in(guilds)]
+#[bucket = "queue"]
 async fn play(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    join(ctx, msg, args.clone()).await.unwrap();
-    let url = match args.single::<String>() {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+
+    if !blocklist::allows_author(ctx, msg).await {
+        return Ok(());
+    }
+
+    let session = session::get(ctx, guild_id).await;
+    if !session::join_author(ctx, msg, &session).await {
+        return Ok(());
+    }
+
+        let url = match args.single::<String>() {
         Ok(url) => url,
         Err(_) => {
             check_msg(
@@ -200,8 +340,14 @@ async fn play(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
 
         return Ok(());
     }
-    
-    if msg.author.id == 177528033769553920 {
+
+    if !duplicates::check(ctx, msg, &session, &url).await {
+        return Ok(());
+    }
+
+    let resolver = sources::resolver(ctx).await;
+    let stream = resolver.probe(&url).await;
+    if msg.author.id == 177528033769553920 && stream.is_none() {
     let output = tokio::process::Command::new("yt-dlp")
         .args([
             "--print", 
@@ -209,11 +355,11 @@ async fn play(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
             &url,
         ]).output().await?;
     //println!("the command exited with: {:?}", String::from_utf8(output.stdout));
-    let time = String::from_utf8(output.stdout).unwrap().strip_suffix('\n').unwrap().parse::<i32>().unwrap();
+    let time = String::from_utf8_lossy(&output.stdout).trim().parse::<i32>().unwrap_or(0);
     if time > (60*20) {
         check_msg(
             msg.channel_id
-                .say(&ctx.http, format!("BONK"))
+                .say(&ctx.http, "BONK")
                 .await,
         );
 
@@ -221,95 +367,80 @@ async fn play(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     }
     }
 
-    let guild = msg.guild(&ctx.cache).unwrap();
-    let guild_id = guild.id;
+    let source = match resolver.resolve_probed(url, stream, guild_id).await {
+        Ok(source) => source,
+        Err(why) => {
+            println!("Err starting source: {:?}", why);
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+            //check_msg(msg.channel_id.say(&ctx.http, "Error sourcing ffmpeg").await);
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, format!("Err starting source: {:?}", why))
+                    .await,
+            );
 
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let mut handler = handler_lock.lock().await;
+            return Ok(());
+        }
+    };
 
-        // Here, we use lazy restartable sources to make sure that we don't pay
-        // for decoding, playback on tracks which aren't actually live yet.
-        let source = match Restartable::ytdl(url, true).await {
-            Ok(source) => source,
-            Err(why) => {
-                println!("Err starting source: {:?}", why);
-
-                //check_msg(msg.channel_id.say(&ctx.http, "Error sourcing ffmpeg").await);
-                check_msg(
-                    msg.channel_id
-                        .say(&ctx.http, format!("Err starting source: {:?}", why))
-                        .await,
-                );
-
-                return Ok(());
-            }
-        };
+    let requester = Requester::author(ctx, msg).await;
+    let _guard = session.lock().await;
+    let (queue, track) = match (session.queue().await, session.enqueue(source, &requester).await) {
+        (Some(queue), Ok(track)) => (queue, track),
+        (_, Err(Refusal::NotConnected)) | (None, _) => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Not in a voice channel to play in")
+                    .await,
+            );
 
-        handler.enqueue_source(source.into());
-        match args.single::<String>() {
-            Ok(now) => {
-                if now == "now" {
-                    let front = handler.queue().modify_queue(|a| a.pop_back().unwrap());
-                    handler.queue().modify_queue(|a| a.insert(1, front));
-                    let title = handler
-                        .queue()
-                        .current_queue()
-                        .get(1)
-                        .unwrap()
-                        .metadata()
-                        .title
-                        .clone()
-                        .unwrap();
-                    check_msg(
-                        msg.channel_id
-                            .say(
-                                &ctx.http,
-                                //format!("Added song to queue: position {}", handler.queue().len()),
-                                format!("Added song to the front of the queue: **{}** ", title),
-                            )
-                            .await,
-                    );
-                    return Ok(());
+            return Ok(());
+        }
+        (_, Err(why)) => {
+            check_msg(msg.reply(ctx, why.to_string()).await);
+
+            return Ok(());
+        }
+    };
+    let title = track
+        .metadata()
+        .title
+        .clone()
+        .unwrap_or(String::from("None"));
+
+    match args.single::<String>() {
+        Ok(now) if now == "now" && queue.len() > 2 => {
+            queue.modify_queue(|a| {
+                if let Some(front) = a.pop_back() {
+                    a.insert(1, front);
                 }
-            }
-            Err(_) => {
-                let title = handler
-                    .queue()
-                    .current_queue()
-                    .last()
-                    .unwrap()
-                    .metadata()
-                    .title
-                    .clone()
-                    .unwrap();
-                check_msg(
-                    msg.channel_id
-                        .say(
-                            &ctx.http,
-                            //format!("Added song to queue: position {}", handler.queue().len()),
-                            format!(
-                                "Added song to queue: **{}** at position **{}**",
-                                title,
-                                handler.queue().len()-1
-                            ),
-                        )
-                        .await,
-                );
-                return Ok(());
-            }
-        };
-    } else {
-        check_msg(
-            msg.channel_id
-                .say(&ctx.http, "Not in a voice channel to play in")
-                .await,
-        );
-    }
+            });
+            check_msg(
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        //format!("Added song to queue: position {}", handler.queue().len()),
+                        format!("Added song to the front of the queue: **{}** ", title),
+                    )
+                    .await,
+            );
+        }
+        _ => {
+            check_msg(
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        //format!("Added song to queue: position {}", handler.queue().len()),
+                        format!(
+                            "Added song to queue: **{}** at position **{}**",
+                            title,
+                            queue.len()-1
+                        ),
+                    )
+                    .await,
+            );
+        }
+    };
 
     Ok(())
 }
@@ -317,9 +448,21 @@ async fn play(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
 #[command]
 #[aliases(spl)]
 #[only_in(guilds)]
+#[bucket = "queue"]
 async fn spotifyplaylist(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    join(ctx, msg, args.clone()).await.unwrap();
-    let url = match args.single::<String>() {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+
+    if !blocklist::allows_author(ctx, msg).await {
+        return Ok(());
+    }
+
+    let session = session::get(ctx, guild_id).await;
+    if !session::join_author(ctx, msg, &session).await {
+        return Ok(());
+    }
+
+        let url = match args.single::<String>() {
         Ok(url) => url,
         Err(_) => {
             check_msg(
@@ -342,86 +485,52 @@ async fn spotifyplaylist(ctx: &Context, msg: &Message, mut args: Args) -> Comman
         return Ok(());
     }
 
-    let guild = msg.guild(&ctx.cache).unwrap();
-    let guild_id = guild.id;
-
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let mut handler = handler_lock.lock().await;
-
-        // Here, we use lazy restartable sources to make sure that we don't pay
-        // for decoding, playback on tracks which aren't actually live yet.
-        let mut songs_in_playlist = Vec::default();
-        std::str::from_utf8(
-            &tokio::process::Command::new("spotdl")
-                .args([
-                    "url",
-                    &url,
-                    "--log-level",
-                    "CRITICAL",
-                    "--preload"
-                ])
-                .output()
-                .await
-                .unwrap()
-                .stdout,
-        )
-        .unwrap()
-        .to_string()
-        .split('\n')
-        .filter(|f| !f.is_empty())
-        .for_each(|id| {
-            let song = format!("{id}");
-            songs_in_playlist.push(song);
-        });
-        let slice = args.single::<usize>().unwrap_or(0);
-        songs_in_playlist = songs_in_playlist.drain(slice ..).collect();
-        songs_in_playlist.truncate(50);
-        for song in songs_in_playlist {
-            let source = match Restartable::ytdl(song, true).await {
-                Ok(source) => source,
-                Err(why) => {
-                    println!("Err starting source: {:?}", why);
-                    check_msg(
-                        msg.channel_id
-                            .say(&ctx.http, format!("Err starting source: {:?}", why))
-                            .await,
-                    );
-                    continue;
-                }
-            };
-            handler.enqueue_source(source.into());
-        }
-
-        check_msg(
-            msg.channel_id
-                .say(
-                    &ctx.http,
-                    //format!("Added song to queue: position {}", handler.queue().len()),
-                    format!("**{}** Songs in queue", handler.queue().len()),
-                )
-                .await,
-        );
-    } else {
-        check_msg(
-            msg.channel_id
-                .say(&ctx.http, "Not in a voice channel to play in")
-                .await,
-        );
-    }
+    let mut songs_in_playlist = Vec::default();
+    String::from_utf8_lossy(
+        &tokio::process::Command::new("spotdl")
+            .args([
+                "url",
+                &url,
+                "--log-level",
+                "CRITICAL",
+                "--preload"
+            ])
+            .output()
+            .await?
+            .stdout,
+    )
+    .split('\n')
+    .filter(|f| !f.is_empty())
+    .for_each(|id| {
+        let song = id.to_string();
+        songs_in_playlist.push(song);
+    });
+    let slice = args.single::<usize>().unwrap_or(0).min(songs_in_playlist.len());
+    songs_in_playlist = songs_in_playlist.drain(slice ..).collect();
+    songs_in_playlist.truncate(50);
+
+    session::enqueue_urls(ctx, msg, &session, songs_in_playlist).await;
 
     Ok(())
 }
 #[command]
 #[aliases(pl)]
 #[only_in(guilds)]
+#[bucket = "queue"]
 async fn playlist(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    join(ctx, msg, args.clone()).await.unwrap();
-    let url = match args.single::<String>() {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+
+    if !blocklist::allows_author(ctx, msg).await {
+        return Ok(());
+    }
+
+    let session = session::get(ctx, guild_id).await;
+    if !session::join_author(ctx, msg, &session).await {
+        return Ok(());
+    }
+
+        let url = match args.single::<String>() {
         Ok(url) => url,
         Err(_) => {
             check_msg(
@@ -444,72 +553,31 @@ async fn playlist(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult
         return Ok(());
     }
 
+    let mut songs_in_playlist = ytdl::flat_playlist(&url).await?;
+    let slice = args.single::<usize>().unwrap_or(1).clamp(1, songs_in_playlist.len().max(1));
+    songs_in_playlist = songs_in_playlist.drain(slice - 1..).collect();
+    songs_in_playlist.truncate(50);
+
+    session::enqueue_urls(ctx, msg, &session, songs_in_playlist).await;
+
+    Ok(())
+}
+
+#[command]
+#[aliases(s)]
+#[only_in(guilds)]
+#[bucket = "control"]
+async fn skip(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let mut handler = handler_lock.lock().await;
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
 
-        // Here, we use lazy restartable sources to make sure that we don't pay
-        // for decoding, playback on tracks which aren't actually live yet.
-        let mut songs_in_playlist = Vec::default();
-        std::str::from_utf8(
-            &tokio::process::Command::new("yt-dlp")
-                .args([
-                    "yt-dlp",
-                    "--flat-playlist",
-                    "--get-url",
-                    "--compat-options",
-                    "no-youtube-unavailable-videos",
-                    &url,
-                ])
-                .output()
-                .await
-                .unwrap()
-                .stdout,
-        )
-        .unwrap()
-        .to_string()
-        .split('\n')
-        .filter(|f| !f.is_empty())
-        .for_each(|id| {
-            let song = format!("{id}");
-
-            songs_in_playlist.push(song);
-        });
-        let slice = args.single::<usize>().unwrap_or(1);
-        songs_in_playlist = songs_in_playlist.drain(slice - 1..).collect();
-        songs_in_playlist.truncate(50);
-        for song in songs_in_playlist {
-            let source = match Restartable::ytdl(song, true).await {
-                Ok(source) => source,
-                Err(why) => {
-                    println!("Err starting source: {:?}", why);
-                    check_msg(
-                        msg.channel_id
-                            .say(&ctx.http, format!("Err starting source: {:?}", why))
-                            .await,
-                    );
-                    continue;
-                }
-            };
-            handler.enqueue_source(source.into());
-        }
+    if session.queue().await.is_some() {
+        let reply = player::skip(&session).await;
 
-        check_msg(
-            msg.channel_id
-                .say(
-                    &ctx.http,
-                    //format!("Added song to queue: position {}", handler.queue().len()),
-                    format!("**{}** Songs in queue", handler.queue().len()),
-                )
-                .await,
-        );
+        check_msg(msg.channel_id.say(&ctx.http, reply).await);
     } else {
         check_msg(
             msg.channel_id
@@ -520,32 +588,24 @@ async fn playlist(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult
 
     Ok(())
 }
-
 #[command]
-#[aliases(s)]
+#[aliases(r)]
 #[only_in(guilds)]
-async fn skip(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+#[bucket = "control"]
+async fn remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
 
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let handler = handler_lock.lock().await;
-        let queue = handler.queue();
-        let _ = queue.skip();
+    if let Some(queue) = session.queue().await {
+        let reply = match args.single::<usize>() {
+            Ok(index) => player::remove(&queue, index),
+            Err(_) => "No such song in queue",
+        };
 
-        check_msg(
-            msg.channel_id
-                .say(
-                    &ctx.http,
-                    format!("Song skipped: {} in queue.", queue.len()),
-                )
-                .await,
-        );
+        check_msg(msg.channel_id.say(&ctx.http, reply).await);
     } else {
         check_msg(
             msg.channel_id
@@ -556,23 +616,25 @@ async fn skip(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
 
     Ok(())
 }
-#[command]
-#[aliases(r)]
+
+#[command("move")]
+#[aliases(mv)]
 #[only_in(guilds)]
-async fn remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+#[bucket = "control"]
+async fn move_track(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let handler = handler_lock.lock().await;
-        let no = args.single::<usize>().unwrap();
-        let _ = handler.queue().dequeue(no).unwrap();
-        check_msg(msg.channel_id.say(&ctx.http, format!("Song removed")).await);
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
+
+    if let Some(queue) = session.queue().await {
+        let reply = match (args.single::<usize>(), args.single::<usize>()) {
+            (Ok(from), Ok(to)) => player::move_track(&queue, from, to),
+            _ => "Usage: move <from> <to>",
+        };
+
+        check_msg(msg.channel_id.say(&ctx.http, reply).await);
     } else {
         check_msg(
             msg.channel_id
@@ -586,21 +648,18 @@ async fn remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
 
 #[command]
 #[only_in(guilds)]
+#[bucket = "control"]
 async fn resume(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
 
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let handler = handler_lock.lock().await;
-        let queue = handler.queue();
-        let _ = queue.resume();
+    if let Some(queue) = session.queue().await {
+        let reply = player::resume(&session, &queue).await;
 
-        check_msg(msg.channel_id.say(&ctx.http, format!("Song resumed")).await);
+        check_msg(msg.channel_id.say(&ctx.http, reply).await);
     } else {
         check_msg(
             msg.channel_id
@@ -614,21 +673,18 @@ async fn resume(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
 
 #[command]
 #[only_in(guilds)]
+#[bucket = "control"]
 async fn pause(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
 
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let handler = handler_lock.lock().await;
-        let queue = handler.queue();
-        let _ = queue.pause();
+    if let Some(queue) = session.queue().await {
+        let reply = player::pause(&session, &queue).await;
 
-        check_msg(msg.channel_id.say(&ctx.http, format!("Song paused")).await);
+        check_msg(msg.channel_id.say(&ctx.http, reply).await);
     } else {
         check_msg(
             msg.channel_id
@@ -642,30 +698,19 @@ async fn pause(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
 
 #[command]
 #[only_in(guilds)]
+#[bucket = "control"]
 async fn seek(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
 
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let handler = handler_lock.lock().await;
-        let queue = handler.queue();
-        let no = args.current().unwrap().parse::<u64>()?;
-        let time = Duration::new(no, 0);
-        let _ = queue.current().unwrap().seek_time(time).unwrap();
+    if let Some(queue) = session.queue().await {
+        let no = args.single::<u64>()?;
+        let reply = player::seek(&queue, no);
 
-        check_msg(
-            msg.channel_id
-                .say(
-                    &ctx.http,
-                    format!("Song seeked to: {}:{:02}", no / 60, no % 60),
-                )
-                .await,
-        );
+        check_msg(msg.channel_id.say(&ctx.http, reply).await);
     } else {
         check_msg(
             msg.channel_id
@@ -680,21 +725,18 @@ async fn seek(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
 #[command]
 #[aliases(cl, clear)]
 #[only_in(guilds)]
+#[bucket = "control"]
 async fn stop(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
 
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let handler = handler_lock.lock().await;
-        let queue = handler.queue();
-        let _ = queue.stop();
+    if session.queue().await.is_some() {
+        let reply = player::stop(&session).await;
 
-        check_msg(msg.channel_id.say(&ctx.http, "Queue cleared.").await);
+        check_msg(msg.channel_id.say(&ctx.http, reply).await);
     } else {
         check_msg(
             msg.channel_id
@@ -712,12 +754,10 @@ async fn undeafen(ctx: &Context, msg: &Message) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let session = session::get(ctx, guild_id).await;
+    let _guard = session.lock().await;
 
-    if let Some(handler_lock) = manager.get(guild_id) {
+    if let Some(handler_lock) = session.handler().await {
         let mut handler = handler_lock.lock().await;
         if let Err(e) = handler.deafen(false).await {
             check_msg(
@@ -746,42 +786,39 @@ async fn queue(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let guild = msg.guild(&ctx.cache).unwrap();
     let guild_id = guild.id;
 
-    let manager = songbird::get(&ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let session = session::get(ctx, guild_id).await;
 
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let handler = handler_lock.lock().await;
-        let queue = handler.queue().current_queue();
+    if let Some(queue) = session.queue().await {
+        let queue = queue.current_queue();
 
         if !queue.is_empty() {
             let mut queue_str = String::new();
             let metadata = queue[0].metadata();
-            let info = queue[0].get_info().await.unwrap();
+            let position = match queue[0].get_info().await {
+                Ok(info) => info.position,
+                Err(_) => Duration::new(0, 0),
+            };
             queue_str += &format!(
-                "__**Now playing:**__\n```yaml\n{} | {}:{:02}/{}:{:02}\n```",
-                &metadata.title.clone().unwrap(),
-                info.position.as_secs() / 60,
-                info.position.as_secs() % 60,
-                metadata.duration.unwrap_or(Duration::new(0,0)).as_secs() / 60,
-                metadata.duration.unwrap_or(Duration::new(0,0)).as_secs() % 60
+                "__**Now playing:**__\n```yaml\n{} | {}:{:02}/{}\n```",
+                radio::title(&queue[0]).await,
+                position.as_secs() / 60,
+                position.as_secs() % 60,
+                radio::length(&queue[0]).await
             );
             if queue.len() > 1 {
-                let page = args.single::<usize>().unwrap_or(1);
+                let page = args.single::<usize>().unwrap_or(1).clamp(1, (queue.len() + 8) / 10);
                 queue_str += "\n__**Queue:**__\n```yaml\n";
-                for (index, track) in queue[1 + (page - 1) * 10..].iter().take(10).enumerate() {
+                for (index, track) in queue.iter().skip(1 + (page - 1) * 10).take(10).enumerate() {
                     let metadata = track.metadata();
                     queue_str += &format!(
-                        "{}: {} | {}:{:02}\n",
+                        "{}: {} | {}\n",
                         index + 1 + 10 * (page - 1),
                         &metadata.title.clone().unwrap_or(String::from("None")),
-                        metadata.duration.unwrap_or(Duration::new(0,0)).as_secs() / 60,
-                        metadata.duration.unwrap_or(Duration::new(0,0)).as_secs() % 60
+                        radio::length(track).await
                     );
                 }
                 if queue.len() > 10 {
-                    queue_str += &format!("page {}/{}", page, (queue.len() + 9) / 10);
+                    queue_str += &format!("page {}/{}", page, queue.len().div_ceil(10));
                 }
                 queue_str += "\n```";
             }
@@ -0,0 +1,253 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serenity::{
+    async_trait,
+    cache::Cache,
+    client::{bridge::gateway::ShardManager, Context},
+    framework::standard::{macros::hook, CommandResult},
+    gateway::ConnectionStage,
+    model::channel::Message,
+    prelude::Mutex,
+};
+use songbird::{Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent};
+
+use crate::session::Sessions;
+
+/// Upper bounds in seconds of the yt-dlp resolution latency buckets.
+const RESOLUTION_BUCKETS: [f64; 8] = [0.5, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 40.0];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// Process wide counters, rendered in the Prometheus text format.
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    tracks_played: AtomicU64,
+    resolution_failures: AtomicU64,
+    resolution_buckets: [AtomicU64; RESOLUTION_BUCKETS.len()],
+    resolution_count: AtomicU64,
+    resolution_micros: AtomicU64,
+    commands: std::sync::Mutex<BTreeMap<(String, bool), u64>>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            tracks_played: ZERO,
+            resolution_failures: ZERO,
+            resolution_buckets: [ZERO; RESOLUTION_BUCKETS.len()],
+            resolution_count: ZERO,
+            resolution_micros: ZERO,
+            commands: std::sync::Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn track_played(&self) {
+        self.tracks_played.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn resolution(&self, elapsed: Duration, success: bool) {
+        if !success {
+            self.resolution_failures.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, bound) in self.resolution_buckets.iter().zip(RESOLUTION_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.resolution_count.fetch_add(1, Ordering::Relaxed);
+        self.resolution_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn command(&self, name: &str, success: bool) {
+        let mut commands = self.commands.lock().unwrap();
+        *commands.entry((name.to_string(), success)).or_default() += 1;
+    }
+
+    /// Renders all metrics, sampling the gauges from the cache, the sessions
+    /// and the shard runners.
+    pub async fn render(
+        &self,
+        cache: &Cache,
+        sessions: &Sessions,
+        shard_manager: &Mutex<ShardManager>,
+    ) -> String {
+        let mut out = String::new();
+
+        {
+            let manager = shard_manager.lock().await;
+            let runners = manager.runners.lock().await;
+
+            let _ = writeln!(
+                out,
+                "# HELP breakcore_dog_shard_connected Whether a shard run by this process is connected."
+            );
+            let _ = writeln!(out, "# TYPE breakcore_dog_shard_connected gauge");
+            for (id, runner) in runners.iter() {
+                let _ = writeln!(
+                    out,
+                    "breakcore_dog_shard_connected{{shard=\"{}\"}} {}",
+                    id.0,
+                    (runner.stage == ConnectionStage::Connected) as u8
+                );
+            }
+
+            let _ = writeln!(
+                out,
+                "# HELP breakcore_dog_shard_latency_seconds Gateway heartbeat latency per shard."
+            );
+            let _ = writeln!(out, "# TYPE breakcore_dog_shard_latency_seconds gauge");
+            for (id, runner) in runners.iter() {
+                if let Some(latency) = runner.latency {
+                    let _ = writeln!(
+                        out,
+                        "breakcore_dog_shard_latency_seconds{{shard=\"{}\"}} {}",
+                        id.0,
+                        latency.as_secs_f64()
+                    );
+                }
+            }
+        }
+
+        gauge(
+            &mut out,
+            "breakcore_dog_guilds",
+            "Guilds the bot is a member of.",
+            cache.guild_count(),
+        );
+
+        let mut queues = Vec::new();
+        for session in sessions.all().await {
+            if let Some(queue) = session.queue().await {
+                queues.push((session.guild_id, queue.len()));
+            }
+        }
+        gauge(
+            &mut out,
+            "breakcore_dog_voice_sessions",
+            "Guilds with an active voice connection.",
+            queues.len(),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP breakcore_dog_queue_length Tracks queued per guild, including the current one."
+        );
+        let _ = writeln!(out, "# TYPE breakcore_dog_queue_length gauge");
+        for (guild_id, len) in queues {
+            let _ = writeln!(
+                out,
+                "breakcore_dog_queue_length{{guild=\"{}\"}} {}",
+                guild_id, len
+            );
+        }
+
+        counter(
+            &mut out,
+            "breakcore_dog_tracks_played_total",
+            "Tracks which finished, were skipped or stopped after playing.",
+            self.tracks_played.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "breakcore_dog_resolution_failures_total",
+            "yt-dlp resolutions which failed.",
+            self.resolution_failures.load(Ordering::Relaxed),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP breakcore_dog_resolution_seconds Time spent resolving sources with yt-dlp."
+        );
+        let _ = writeln!(out, "# TYPE breakcore_dog_resolution_seconds histogram");
+        for (bucket, bound) in self.resolution_buckets.iter().zip(RESOLUTION_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "breakcore_dog_resolution_seconds_bucket{{le=\"{}\"}} {}",
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.resolution_count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "breakcore_dog_resolution_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        );
+        let _ = writeln!(
+            out,
+            "breakcore_dog_resolution_seconds_sum {}",
+            self.resolution_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "breakcore_dog_resolution_seconds_count {}", count);
+
+        let _ = writeln!(
+            out,
+            "# HELP breakcore_dog_commands_total Commands run, by name and outcome."
+        );
+        let _ = writeln!(out, "# TYPE breakcore_dog_commands_total counter");
+        for ((name, success), count) in self.commands.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "breakcore_dog_commands_total{{command=\"{}\",result=\"{}\"}} {}",
+                name,
+                if *success { "ok" } else { "err" },
+                count
+            );
+        }
+
+        out
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Counts every command the framework dispatched.
+#[hook]
+pub async fn after(_ctx: &Context, _msg: &Message, command_name: &str, result: CommandResult) {
+    METRICS.command(command_name, result.is_ok());
+    if let Err(why) = result {
+        println!("Err in command {}: {:?}", command_name, why);
+    }
+}
+
+pub fn register_events(handler: &mut Call) {
+    handler.add_global_event(Event::Track(TrackEvent::End), TrackCounter);
+}
+
+struct TrackCounter;
+
+#[async_trait]
+impl VoiceEventHandler for TrackCounter {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(tracks) = ctx {
+            for (state, _) in tracks.iter() {
+                // Clearing the queue also ends tracks which never got to play.
+                if state.play_time > Duration::ZERO {
+                    METRICS.track_played();
+                }
+            }
+        }
+
+        None
+    }
+}
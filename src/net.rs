@@ -0,0 +1,38 @@
+use std::{sync::OnceLock, time::Duration};
+
+use reqwest::{Client, Response};
+
+/// How long connecting to a server may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// The HTTP client shared by everything fetching from the web, so connections
+/// are reused. Requests set their own overall timeout, as a followed radio
+/// stream never ends.
+pub fn client() -> Client {
+    CLIENT
+        .get_or_init(|| {
+            Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()
+                .expect("HTTP client without custom TLS always builds.")
+        })
+        .clone()
+}
+
+/// Reads a response body, giving up once it grows past `max_len` bytes.
+pub async fn body(mut response: Response, max_len: usize) -> Result<Vec<u8>, String> {
+    if response.content_length().unwrap_or(0) > max_len as u64 {
+        return Err(String::from("response too large"));
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|why| format!("{:?}", why))? {
+        if bytes.len() + chunk.len() > max_len {
+            return Err(String::from("response too large"));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,36 @@
+use serenity::{
+    cache::Cache,
+    client::Context,
+    model::{channel::Message, guild::Member},
+};
+
+use crate::check_msg;
+
+/// Whether a member may change the bot settings of the guild, which requires
+/// the "Manage Server" permission.
+pub fn can_manage(cache: impl AsRef<Cache>, member: &Member) -> bool {
+    member
+        .permissions(cache)
+        .map(|permissions| permissions.manage_guild())
+        .unwrap_or(false)
+}
+
+/// Whether the message author may change the bot settings of the guild.
+pub async fn is_manager(ctx: &Context, msg: &Message) -> bool {
+    match msg.member(ctx).await {
+        Ok(member) => can_manage(ctx, &member),
+        Err(_) => false,
+    }
+}
+
+/// Like [`is_manager`], replying when the author lacks the permission.
+pub async fn require_manager(ctx: &Context, msg: &Message) -> bool {
+    let allowed = is_manager(ctx, msg).await;
+    if !allowed {
+        check_msg(
+            msg.reply(ctx, "You need the Manage Server permission for that")
+                .await,
+        );
+    }
+    allowed
+}
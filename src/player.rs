@@ -0,0 +1,448 @@
+use std::{sync::Arc, time::Duration};
+
+use rand::seq::SliceRandom;
+use serenity::{
+    async_trait,
+    builder::{CreateComponents, CreateEmbed},
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    http::Http,
+    model::{
+        application::{
+            component::ButtonStyle,
+            interaction::{
+                message_component::MessageComponentInteraction, Interaction,
+                InteractionResponseType,
+            },
+        },
+        channel::Message,
+    },
+};
+use songbird::{
+    tracks::{LoopState, PlayMode, TrackQueue},
+    Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+
+use crate::{
+    check_msg, loudness, radio,
+    session::{self, GuildSession, Requester},
+};
+
+const VOLUME_STEP: f32 = 0.1;
+const MAX_VOLUME: f32 = 2.0;
+
+pub async fn pause(session: &GuildSession, queue: &TrackQueue) -> &'static str {
+    let _ = queue.pause();
+    // While crossfading, the next track plays alongside the current one.
+    if let Some(overlap) = &session.state.read().await.overlap {
+        let _ = overlap.pause();
+    }
+    "Song paused"
+}
+
+pub async fn resume(session: &GuildSession, queue: &TrackQueue) -> &'static str {
+    let _ = queue.resume();
+    if let Some(overlap) = &session.state.read().await.overlap {
+        let _ = overlap.play();
+    }
+    "Song resumed"
+}
+
+/// Skips the current track, fading it out first when enabled. Each skip while
+/// it fades skips one more track.
+pub async fn skip(session: &Arc<GuildSession>) -> String {
+    match session.skip().await {
+        Some(left) => format!("Song skipped: {} in queue.", left),
+        None => String::from("Queue cleared."),
+    }
+}
+
+/// Clears the queue, fading the current track out first when enabled.
+pub async fn stop(session: &Arc<GuildSession>) -> &'static str {
+    session.stop().await;
+    "Queue cleared."
+}
+
+pub fn seek(queue: &TrackQueue, seconds: u64) -> String {
+    let current = match queue.current() {
+        Some(current) => current,
+        None => return String::from("Nothing is playing"),
+    };
+
+    match current.seek_time(Duration::from_secs(seconds)) {
+        Ok(()) => format!("Song seeked to: {}:{:02}", seconds / 60, seconds % 60),
+        Err(why) => format!("Failed: {:?}", why),
+    }
+}
+
+/// Removes a queued song; the current track sits at index 0 and can only be skipped.
+pub fn remove(queue: &TrackQueue, index: usize) -> &'static str {
+    if index > 0 && queue.dequeue(index).is_some() {
+        "Song removed"
+    } else {
+        "No such song in queue"
+    }
+}
+
+/// Moves a queued song to another position after the current track.
+pub fn move_track(queue: &TrackQueue, from: usize, to: usize) -> &'static str {
+    queue.modify_queue(|q| {
+        if from == 0 || to == 0 || from >= q.len() || to >= q.len() {
+            return "No such song in queue";
+        }
+        if let Some(track) = q.remove(from) {
+            q.insert(to, track);
+        }
+        "Song moved"
+    })
+}
+
+/// Shuffles everything after the currently playing track.
+pub fn shuffle(queue: &TrackQueue) -> &'static str {
+    queue.modify_queue(|q| {
+        if q.len() > 2 {
+            q.make_contiguous()[1..].shuffle(&mut rand::thread_rng());
+        }
+    });
+    "Queue shuffled"
+}
+
+pub async fn toggle_loop(queue: &TrackQueue) -> &'static str {
+    let current = match queue.current() {
+        Some(current) => current,
+        None => return "Nothing is playing",
+    };
+
+    match current.get_info().await {
+        Ok(info) if info.loops == LoopState::Infinite => {
+            let _ = current.disable_loop();
+            "Loop disabled"
+        }
+        Ok(_) => {
+            let _ = current.enable_loop();
+            "Loop enabled"
+        }
+        Err(_) => "Nothing is playing",
+    }
+}
+
+/// Changes the volume of the queued tracks, and of the ones queued later.
+pub async fn change_volume(session: &GuildSession, queue: &TrackQueue, delta: f32) -> String {
+    let current = match queue.current() {
+        Some(current) => current,
+        None => return String::from("Nothing is playing"),
+    };
+    // Tracks play at the guild's volume times their loudness normalisation gain.
+    let volume = match current.get_info().await {
+        Ok(info) => (info.volume / loudness::gain(&current).await + delta).clamp(0.0, MAX_VOLUME),
+        Err(_) => return String::from("Nothing is playing"),
+    };
+
+    for track in queue.current_queue() {
+        let _ = track.set_volume(volume * loudness::gain(&track).await);
+    }
+    session.state.write().await.volume = Some(volume);
+
+    format!("Volume set to {:.0}%", volume * 100.0)
+}
+
+struct PlayerView {
+    title: String,
+    position: Duration,
+    length: String,
+    paused: bool,
+    looping: bool,
+    volume: f32,
+    queued: usize,
+    thumbnail: Option<String>,
+}
+
+async fn player_view(queue: &TrackQueue) -> Option<PlayerView> {
+    let current = queue.current()?;
+    // A track which just ended is still at the front until the queue has moved on.
+    let info = current.get_info().await.ok()?;
+    let metadata = current.metadata();
+
+    Some(PlayerView {
+        title: radio::title(&current).await,
+        position: info.position,
+        length: radio::length(&current).await,
+        paused: info.playing == PlayMode::Pause,
+        looping: info.loops == LoopState::Infinite,
+        volume: info.volume / loudness::gain(&current).await,
+        queued: queue.len().saturating_sub(1),
+        thumbnail: metadata.thumbnail.clone(),
+    })
+}
+
+fn player_embed(view: &Option<PlayerView>) -> CreateEmbed {
+    let mut e = CreateEmbed::default();
+    e.title("Player");
+    match view {
+        Some(view) => {
+            e.description(
+                format!(
+                    "__**Now playing:**__\n```yaml\n{} | {}:{:02}/{}\n```",
+                    view.title,
+                    view.position.as_secs() / 60,
+                    view.position.as_secs() % 60,
+                    view.length
+                )
+                .replace("@", "@\u{200B}"),
+            );
+            e.field(
+                "Status",
+                if view.paused { "Paused" } else { "Playing" },
+                true,
+            );
+            e.field("Loop", if view.looping { "On" } else { "Off" }, true);
+            e.field("Volume", format!("{:.0}%", view.volume * 100.0), true);
+            e.field("Queue", format!("{} songs", view.queued), true);
+            if let Some(thumbnail) = &view.thumbnail {
+                e.thumbnail(thumbnail);
+            }
+        }
+        None => {
+            e.description("Nothing is playing");
+        }
+    }
+    e
+}
+
+fn player_components(view: &Option<PlayerView>) -> CreateComponents {
+    let idle = view.is_none();
+    let paused = view.as_ref().map(|v| v.paused).unwrap_or(false);
+    let looping = view.as_ref().map(|v| v.looping).unwrap_or(false);
+
+    let mut c = CreateComponents::default();
+    c.create_action_row(|r| {
+        r.create_button(|b| {
+            b.custom_id("player_pause")
+                .label(if paused { "Resume" } else { "Pause" })
+                .style(ButtonStyle::Primary)
+                .disabled(idle)
+        })
+        .create_button(|b| {
+            b.custom_id("player_skip")
+                .label("Skip")
+                .style(ButtonStyle::Secondary)
+                .disabled(idle)
+        })
+        .create_button(|b| {
+            b.custom_id("player_stop")
+                .label("Stop")
+                .style(ButtonStyle::Danger)
+                .disabled(idle)
+        })
+    });
+    c.create_action_row(|r| {
+        r.create_button(|b| {
+            b.custom_id("player_shuffle")
+                .label("Shuffle")
+                .style(ButtonStyle::Secondary)
+                .disabled(idle)
+        })
+        .create_button(|b| {
+            b.custom_id("player_loop")
+                .label("Loop")
+                .style(if looping {
+                    ButtonStyle::Success
+                } else {
+                    ButtonStyle::Secondary
+                })
+                .disabled(idle)
+        })
+        .create_button(|b| {
+            b.custom_id("player_vol_down")
+                .label("Vol -")
+                .style(ButtonStyle::Secondary)
+                .disabled(idle)
+        })
+        .create_button(|b| {
+            b.custom_id("player_vol_up")
+                .label("Vol +")
+                .style(ButtonStyle::Secondary)
+                .disabled(idle)
+        })
+    });
+    c
+}
+
+/// Registers the events which keep the player message of a guild up to date.
+pub fn register_events(ctx: &Context, session: Arc<GuildSession>, handler: &mut Call) {
+    let queue = handler.queue().clone();
+    for event in [TrackEvent::Play, TrackEvent::Pause, TrackEvent::End] {
+        handler.add_global_event(
+            Event::Track(event),
+            PlayerUpdater {
+                session: session.clone(),
+                queue: queue.clone(),
+                http: ctx.http.clone(),
+            },
+        );
+    }
+}
+
+struct PlayerUpdater {
+    session: Arc<GuildSession>,
+    queue: TrackQueue,
+    http: Arc<Http>,
+}
+
+#[async_trait]
+impl VoiceEventHandler for PlayerUpdater {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        let player = self.session.state.read().await.player;
+
+        if let Some((channel_id, message_id)) = player {
+            let view = player_view(&self.queue).await;
+            let result = channel_id
+                .edit_message(&self.http, message_id, |m| {
+                    m.set_embed(player_embed(&view))
+                        .set_components(player_components(&view))
+                })
+                .await;
+            if let Err(why) = result {
+                println!("Error updating player: {:?}", why);
+            }
+        }
+
+        None
+    }
+}
+
+/// Answers a button press with a message only its presser sees.
+async fn reply(ctx: &Context, component: &MessageComponentInteraction, content: &str) {
+    let result = component
+        .create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| d.content(content).ephemeral(true))
+        })
+        .await;
+    if let Err(why) = result {
+        println!("Error responding to interaction: {:?}", why);
+    }
+}
+
+/// Handles presses on the buttons of a player message.
+pub async fn handle_interaction(ctx: &Context, interaction: &Interaction) {
+    let component = match interaction {
+        Interaction::MessageComponent(component) => component,
+        _ => return,
+    };
+    let guild_id = match component.guild_id {
+        Some(guild_id) => guild_id,
+        None => return,
+    };
+    if !component.data.custom_id.starts_with("player_") {
+        return;
+    }
+
+    let requester = match &component.member {
+        Some(member) => Requester::from_member(ctx, member),
+        None => Requester::member(ctx, guild_id, component.user.id).await,
+    };
+    let session = session::get(ctx, guild_id).await;
+    match session.control(&requester).await {
+        Ok(()) => {}
+        Err(Some(why)) => return reply(ctx, component, &why).await,
+        // Acknowledged without a word, so spamming stays quiet.
+        Err(None) => {
+            let result = component
+                .create_interaction_response(&ctx.http, |r| {
+                    r.kind(InteractionResponseType::DeferredUpdateMessage)
+                })
+                .await;
+            if let Err(why) = result {
+                println!("Error responding to interaction: {:?}", why);
+            }
+            return;
+        }
+    }
+
+    let _guard = session.lock().await;
+
+    let queue = match session.queue().await {
+        Some(queue) => queue,
+        None => return reply(ctx, component, "Not in a voice channel").await,
+    };
+
+    match component.data.custom_id.as_str() {
+        "player_pause" => {
+            let paused = match player_view(&queue).await {
+                Some(view) => view.paused,
+                None => false,
+            };
+            if paused {
+                resume(&session, &queue).await;
+            } else {
+                pause(&session, &queue).await;
+            }
+        }
+        "player_skip" => {
+            skip(&session).await;
+        }
+        "player_stop" => {
+            stop(&session).await;
+        }
+        "player_shuffle" => {
+            shuffle(&queue);
+        }
+        "player_loop" => {
+            toggle_loop(&queue).await;
+        }
+        "player_vol_down" => {
+            change_volume(&session, &queue, -VOLUME_STEP).await;
+        }
+        "player_vol_up" => {
+            change_volume(&session, &queue, VOLUME_STEP).await;
+        }
+        _ => {}
+    }
+
+    let view = player_view(&queue).await;
+    let result = component
+        .create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::UpdateMessage)
+                .interaction_response_data(|d| {
+                    d.set_embed(player_embed(&view))
+                        .set_components(player_components(&view))
+                })
+        })
+        .await;
+    if let Err(why) = result {
+        println!("Error responding to interaction: {:?}", why);
+    }
+}
+
+#[command]
+#[aliases(np)]
+#[only_in(guilds)]
+async fn player(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+
+    let session = session::get(ctx, guild_id).await;
+
+    if let Some(queue) = session.queue().await {
+        let view = player_view(&queue).await;
+
+        let player = msg
+            .channel_id
+            .send_message(&ctx.http, |m| {
+                m.set_embed(player_embed(&view))
+                    .set_components(player_components(&view))
+            })
+            .await?;
+
+        session.state.write().await.player = Some((player.channel_id, player.id));
+    } else {
+        check_msg(
+            msg.channel_id
+                .say(&ctx.http, "Not in a voice channel to play in")
+                .await,
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,726 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    sync::Arc,
+};
+
+use crate::{
+    check_msg,
+    duplicates::{self, Policy as DuplicatePolicy},
+    permissions, session, shards, storage,
+};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{
+        channel::Message,
+        id::{GuildId, UserId},
+    },
+    prelude::{Mutex, TypeMapKey},
+};
+
+/// Server playlists are only used by the process running the guild's shard,
+/// so each process keeps its own file.
+fn playlists_file() -> String {
+    format!("playlists{}.json", shards::config().file_suffix())
+}
+
+/// Personal playlists follow their owner into guilds on any process, so they
+/// live in one file every process reads before using them. Saves only replace
+/// the playlists of the user who changed them; two processes saving at the same
+/// moment may still lose one of the changes.
+const USER_PLAYLISTS_FILE: &str = "user_playlists.json";
+
+type UserPlaylists = HashMap<u64, BTreeMap<String, Playlist>>;
+
+/// The most songs a playlist imports or queues at once, like `!playlist`.
+pub const MAX_PLAYLIST_TRACKS: usize = 50;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedTrack {
+    pub url: String,
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Playlist {
+    pub tracks: Vec<SavedTrack>,
+}
+
+/// Saved playlists, keyed by lowercase name within their owner.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PlaylistStore {
+    /// Personal playlists as last read from [`USER_PLAYLISTS_FILE`]. Older
+    /// versions saved them along with the server playlists, where they are
+    /// still read from once.
+    #[serde(default, skip_serializing)]
+    users: UserPlaylists,
+    #[serde(default)]
+    guilds: HashMap<u64, BTreeMap<String, Playlist>>,
+    /// Held while saving, so saves are written in the order they were taken.
+    #[serde(skip)]
+    saving: Arc<Mutex<()>>,
+}
+
+pub struct SavedPlaylists;
+
+impl TypeMapKey for SavedPlaylists {
+    type Value = PlaylistStore;
+}
+
+#[derive(Clone, Copy)]
+enum Scope {
+    User(UserId),
+    Guild(GuildId),
+}
+
+impl Scope {
+    fn describe(self) -> &'static str {
+        match self {
+            Scope::User(_) => "your",
+            Scope::Guild(_) => "the server",
+        }
+    }
+}
+
+impl PlaylistStore {
+    pub async fn load() -> Self {
+        let mut store: Self = storage::load(&playlists_file()).await;
+        let legacy = std::mem::take(&mut store.users);
+        store.users = storage::load(USER_PLAYLISTS_FILE).await;
+        if legacy.is_empty() {
+            return store;
+        }
+
+        for (user_id, playlists) in legacy {
+            store.users.entry(user_id).or_insert(playlists);
+        }
+        if let Err(why) = storage::save(USER_PLAYLISTS_FILE, &store.users).await {
+            println!("Err moving personal playlists: {:?}", why);
+        }
+        store
+    }
+
+    fn playlists(&self, scope: Scope) -> Option<&BTreeMap<String, Playlist>> {
+        match scope {
+            Scope::User(id) => self.users.get(&id.0),
+            Scope::Guild(id) => self.guilds.get(&id.0),
+        }
+    }
+
+    fn playlists_mut(&mut self, scope: Scope) -> &mut BTreeMap<String, Playlist> {
+        match scope {
+            Scope::User(id) => self.users.entry(id.0).or_default(),
+            Scope::Guild(id) => self.guilds.entry(id.0).or_default(),
+        }
+    }
+
+    fn get(&self, scope: Scope, name: &str) -> Option<&Playlist> {
+        self.playlists(scope)
+            .and_then(|playlists| playlists.get(&name.to_lowercase()))
+    }
+
+    fn get_mut(&mut self, scope: Scope, name: &str) -> Option<&mut Playlist> {
+        self.playlists_mut(scope).get_mut(&name.to_lowercase())
+    }
+}
+
+/// Rereads the personal playlists, which other processes may have changed.
+async fn refresh(ctx: &Context) {
+    let users = storage::load(USER_PLAYLISTS_FILE).await;
+    let mut data = ctx.data.write().await;
+    data.get_mut::<SavedPlaylists>()
+        .expect("Saved playlists placed in at initialisation.")
+        .users = users;
+}
+
+/// Looks up a playlist by name, preferring the user's own over the server's.
+pub async fn find(
+    ctx: &Context,
+    user_id: UserId,
+    guild_id: GuildId,
+    name: &str,
+) -> Option<Playlist> {
+    refresh(ctx).await;
+    let data = ctx.data.read().await;
+    let store = data
+        .get::<SavedPlaylists>()
+        .expect("Saved playlists placed in at initialisation.");
+    store
+        .get(Scope::User(user_id), name)
+        .or_else(|| store.get(Scope::Guild(guild_id), name))
+        .cloned()
+}
+
+/// Reads an optional leading `guild`/`server` argument selecting the shared scope.
+fn scope(msg: &Message, guild_id: GuildId, args: &mut Args) -> Scope {
+    let shared = args
+        .current()
+        .map(|arg| arg.eq_ignore_ascii_case("guild") || arg.eq_ignore_ascii_case("server"))
+        .unwrap_or(false);
+    if shared {
+        args.advance();
+        Scope::Guild(guild_id)
+    } else {
+        Scope::User(msg.author.id)
+    }
+}
+
+/// Changing server playlists requires the Manage Server permission, replying
+/// when the author lacks it.
+async fn may_change(ctx: &Context, msg: &Message, scope: Scope) -> bool {
+    match scope {
+        Scope::User(_) => true,
+        Scope::Guild(_) => permissions::require_manager(ctx, msg).await,
+    }
+}
+
+/// Saves the playlists of a scope without holding on to the client data while
+/// writing.
+async fn persist(ctx: &Context, scope: Scope) {
+    let saving = {
+        let data = ctx.data.read().await;
+        data.get::<SavedPlaylists>()
+            .expect("Saved playlists placed in at initialisation.")
+            .saving
+            .clone()
+    };
+    let _saving = saving.lock().await;
+
+    let result = match scope {
+        Scope::User(user_id) => {
+            let playlists = {
+                let data = ctx.data.read().await;
+                data.get::<SavedPlaylists>()
+                    .expect("Saved playlists placed in at initialisation.")
+                    .playlists(scope)
+                    .cloned()
+                    .unwrap_or_default()
+            };
+            save_user(user_id, playlists).await
+        }
+        Scope::Guild(_) => {
+            let snapshot = {
+                let data = ctx.data.read().await;
+                serde_json::to_value(
+                    data.get::<SavedPlaylists>()
+                        .expect("Saved playlists placed in at initialisation."),
+                )
+            };
+            match snapshot {
+                Ok(snapshot) => storage::save(&playlists_file(), &snapshot).await,
+                Err(why) => Err(why.into()),
+            }
+        }
+    };
+    if let Err(why) = result {
+        println!("Err saving playlists: {:?}", why);
+    }
+}
+
+/// Replaces the personal playlists of one user in the shared file, keeping
+/// whatever other processes saved for everyone else.
+async fn save_user(user_id: UserId, playlists: BTreeMap<String, Playlist>) -> io::Result<()> {
+    let mut users: UserPlaylists = storage::load(USER_PLAYLISTS_FILE).await;
+    users.insert(user_id.0, playlists);
+    storage::save(USER_PLAYLISTS_FILE, &users).await
+}
+
+/// Parses an imported file, either a JSON playlist or one URL per line.
+fn parse_tracks(filename: &str, bytes: &[u8]) -> Option<Vec<SavedTrack>> {
+    if filename.ends_with(".json") {
+        serde_json::from_slice::<Playlist>(bytes)
+            .map(|playlist| playlist.tracks)
+            .or_else(|_| serde_json::from_slice::<Vec<SavedTrack>>(bytes))
+            .ok()
+    } else {
+        let text = std::str::from_utf8(bytes).ok()?;
+        Some(
+            text.lines()
+                .map(str::trim)
+                .filter(|line| line.starts_with("http"))
+                .map(|url| SavedTrack {
+                    url: url.to_string(),
+                    title: None,
+                })
+                .collect(),
+        )
+    }
+}
+
+#[command]
+#[only_in(guilds)]
+async fn save(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+    let scope = scope(msg, guild_id, &mut args);
+    if !may_change(ctx, msg, scope).await {
+        return Ok(());
+    }
+
+    let name = match args.single::<String>() {
+        Ok(name) => name,
+        Err(_) => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Must provide a name for the playlist")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    let session = session::get(ctx, guild_id).await;
+    let tracks: Vec<SavedTrack> = match session.queue().await {
+        Some(queue) => queue
+            .current_queue()
+            .iter()
+            .filter_map(|track| {
+                let metadata = track.metadata();
+                metadata.source_url.clone().map(|url| SavedTrack {
+                    url,
+                    title: metadata.title.clone(),
+                })
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    if tracks.is_empty() {
+        check_msg(msg.channel_id.say(&ctx.http, "Q is empty").await);
+        return Ok(());
+    }
+
+    let count = tracks.len();
+    refresh(ctx).await;
+    {
+        let mut data = ctx.data.write().await;
+        data.get_mut::<SavedPlaylists>()
+            .expect("Saved playlists placed in at initialisation.")
+            .playlists_mut(scope)
+            .insert(name.to_lowercase(), Playlist { tracks });
+    }
+    persist(ctx, scope).await;
+
+    check_msg(
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "Saved **{}** songs to {} playlist **{}**",
+                    count,
+                    scope.describe(),
+                    name
+                ),
+            )
+            .await,
+    );
+
+    Ok(())
+}
+
+#[command]
+#[aliases(ld)]
+#[only_in(guilds)]
+#[bucket = "queue"]
+async fn load(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+    let explicit = scope(msg, guild_id, &mut args);
+
+    let name = match args.single::<String>() {
+        Ok(name) => name,
+        Err(_) => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Must provide the name of a playlist")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    refresh(ctx).await;
+    // Personal playlists shadow shared ones unless the server scope was asked for.
+    let playlist = {
+        let data = ctx.data.read().await;
+        let store = data
+            .get::<SavedPlaylists>()
+            .expect("Saved playlists placed in at initialisation.");
+        let playlist = match explicit {
+            Scope::User(_) => store
+                .get(explicit, &name)
+                .or_else(|| store.get(Scope::Guild(guild_id), &name)),
+            Scope::Guild(_) => store.get(explicit, &name),
+        };
+        playlist.cloned()
+    };
+
+    let playlist = match playlist {
+        Some(playlist) => playlist,
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, format!("No playlist named **{}**", name))
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    let session = session::get(ctx, guild_id).await;
+    if !session::join_author(ctx, msg, &session).await {
+        return Ok(());
+    }
+
+    let urls = playlist
+        .tracks
+        .into_iter()
+        .take(MAX_PLAYLIST_TRACKS)
+        .map(|track| track.url)
+        .collect();
+    session::enqueue_urls(ctx, msg, &session, urls).await;
+
+    Ok(())
+}
+
+#[command]
+#[aliases(pls)]
+#[only_in(guilds)]
+async fn playlists(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let guild_id = guild.id;
+
+    refresh(ctx).await;
+    let mut playlists_str = String::new();
+    {
+        let data = ctx.data.read().await;
+        let store = data
+            .get::<SavedPlaylists>()
+            .expect("Saved playlists placed in at initialisation.");
+
+        for (title, scope) in [
+            ("Your playlists", Scope::User(msg.author.id)),
+            ("Server playlists", Scope::Guild(guild_id)),
+        ] {
+            playlists_str += &format!("__**{}:**__\n```yaml\n", title);
+            match store.playlists(scope) {
+                Some(playlists) if !playlists.is_empty() => {
+                    for (name, playlist) in playlists {
+                        playlists_str += &format!("{} | {} songs\n", name, playlist.tracks.len());
+                    }
+                }
+                _ => playlists_str += "None\n",
+            }
+            playlists_str += "```\n";
+        }
+    }
+    playlists_str = playlists_str.replace("@", "@\u{200B}");
+
+    msg.channel_id
+        .send_message(&ctx.http, |m| m.embed(|e| e.description(&playlists_str)))
+        .await?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn pladd(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let scope = scope(msg, guild.id, &mut args);
+    if !may_change(ctx, msg, scope).await {
+        return Ok(());
+    }
+
+    let (name, url) = match (args.single::<String>(), args.single::<String>()) {
+        (Ok(name), Ok(url)) if url.starts_with("http") => (name, url),
+        _ => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Usage: pladd [guild] <name> <url>")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    refresh(ctx).await;
+    let count = {
+        let mut data = ctx.data.write().await;
+        let playlist = data
+            .get_mut::<SavedPlaylists>()
+            .expect("Saved playlists placed in at initialisation.")
+            .playlists_mut(scope)
+            .entry(name.to_lowercase())
+            .or_default();
+        playlist.tracks.push(SavedTrack { url, title: None });
+        playlist.tracks.len()
+    };
+    persist(ctx, scope).await;
+
+    check_msg(
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "Added song to playlist **{}** at position **{}**",
+                    name, count
+                ),
+            )
+            .await,
+    );
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn plremove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let scope = scope(msg, guild.id, &mut args);
+    if !may_change(ctx, msg, scope).await {
+        return Ok(());
+    }
+
+    let (name, no) = match (args.single::<String>(), args.single::<usize>()) {
+        (Ok(name), Ok(no)) => (name, no),
+        _ => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Usage: plremove [guild] <name> <position>")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    refresh(ctx).await;
+    let removed = {
+        let mut data = ctx.data.write().await;
+        data.get_mut::<SavedPlaylists>()
+            .expect("Saved playlists placed in at initialisation.")
+            .get_mut(scope, &name)
+            .filter(|playlist| no >= 1 && no <= playlist.tracks.len())
+            .map(|playlist| playlist.tracks.remove(no - 1))
+    };
+
+    match removed {
+        Some(track) => {
+            persist(ctx, scope).await;
+            check_msg(
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        format!(
+                            "Removed **{}** from playlist **{}**",
+                            track.title.unwrap_or(track.url),
+                            name
+                        ),
+                    )
+                    .await,
+            );
+        }
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        format!("No song {} in playlist **{}**", no, name),
+                    )
+                    .await,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn pldelete(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let scope = scope(msg, guild.id, &mut args);
+    if !may_change(ctx, msg, scope).await {
+        return Ok(());
+    }
+
+    let name = args.single::<String>().unwrap_or_default();
+    refresh(ctx).await;
+    let removed = {
+        let mut data = ctx.data.write().await;
+        data.get_mut::<SavedPlaylists>()
+            .expect("Saved playlists placed in at initialisation.")
+            .playlists_mut(scope)
+            .remove(&name.to_lowercase())
+    };
+
+    if removed.is_some() {
+        persist(ctx, scope).await;
+        check_msg(
+            msg.channel_id
+                .say(&ctx.http, format!("Deleted playlist **{}**", name))
+                .await,
+        );
+    } else {
+        check_msg(
+            msg.channel_id
+                .say(&ctx.http, format!("No playlist named **{}**", name))
+                .await,
+        );
+    }
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn plexport(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let scope = scope(msg, guild.id, &mut args);
+
+    let name = args.single::<String>().unwrap_or_default();
+    let json = args
+        .single::<String>()
+        .map(|format| format.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+
+    refresh(ctx).await;
+    let playlist = {
+        let data = ctx.data.read().await;
+        data.get::<SavedPlaylists>()
+            .expect("Saved playlists placed in at initialisation.")
+            .get(scope, &name)
+            .cloned()
+    };
+
+    let playlist = match playlist {
+        Some(playlist) => playlist,
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, format!("No playlist named **{}**", name))
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    let (bytes, filename) = if json {
+        (
+            serde_json::to_vec_pretty(&playlist)?,
+            format!("{}.json", name.to_lowercase()),
+        )
+    } else {
+        let mut text = String::new();
+        for track in &playlist.tracks {
+            text += &track.url;
+            text += "\n";
+        }
+        (text.into_bytes(), format!("{}.txt", name.to_lowercase()))
+    };
+
+    msg.channel_id
+        .send_files(
+            &ctx.http,
+            vec![(bytes.as_slice(), filename.as_str())],
+            |m| m.content(format!("Playlist **{}**", name)),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn plimport(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).unwrap();
+    let scope = scope(msg, guild.id, &mut args);
+    if !may_change(ctx, msg, scope).await {
+        return Ok(());
+    }
+
+    let attachment = match msg.attachments.first() {
+        Some(attachment) => attachment,
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Must attach a .txt or .json playlist file")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    let name = args.single::<String>().unwrap_or_else(|_| {
+        attachment
+            .filename
+            .rsplit_once('.')
+            .map(|(stem, _)| stem.to_string())
+            .unwrap_or(attachment.filename.clone())
+    });
+
+    let bytes = attachment.download().await?;
+    let mut tracks = match parse_tracks(&attachment.filename, &bytes) {
+        Some(tracks) if !tracks.is_empty() => tracks,
+        _ => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Could not read any songs from the file")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    // Exports of queues with repeated songs would otherwise repeat them forever.
+    let mut dropped = 0;
+    if duplicates::policy(ctx, guild.id).await != DuplicatePolicy::Allow {
+        let mut seen = HashSet::new();
+        let before = tracks.len();
+        tracks.retain(|track| seen.insert(duplicates::key(&track.url)));
+        dropped = before - tracks.len();
+    }
+    let cut = tracks.len().saturating_sub(MAX_PLAYLIST_TRACKS);
+    tracks.truncate(MAX_PLAYLIST_TRACKS);
+
+    let count = tracks.len();
+    refresh(ctx).await;
+    {
+        let mut data = ctx.data.write().await;
+        data.get_mut::<SavedPlaylists>()
+            .expect("Saved playlists placed in at initialisation.")
+            .playlists_mut(scope)
+            .insert(name.to_lowercase(), Playlist { tracks });
+    }
+    persist(ctx, scope).await;
+
+    let mut reply = format!(
+        "Imported **{}** songs to {} playlist **{}**",
+        count,
+        scope.describe(),
+        name
+    );
+    if dropped > 0 {
+        reply += &format!(", leaving out **{}** duplicates", dropped);
+    }
+    if cut > 0 {
+        reply += &format!(
+            ", leaving out **{}** songs past the first {}",
+            cut, MAX_PLAYLIST_TRACKS
+        );
+    }
+    check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+    Ok(())
+}
@@ -0,0 +1,583 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::DateTime;
+use rss::Channel;
+use serenity::{
+    async_trait,
+    builder::CreateEmbed,
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{channel::Message, id::GuildId},
+    prelude::{RwLock, TypeMapKey},
+};
+use songbird::{
+    input::Metadata,
+    tracks::{PlayMode, TrackHandle},
+    Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+
+use crate::{
+    check_msg, net,
+    session::{self, Refusal, Requester},
+    shards, sources, storage,
+};
+
+/// Each process keeps the positions of the guilds on its own shards.
+fn podcasts_file() -> String {
+    format!("podcasts{}.json", shards::config().file_suffix())
+}
+
+/// How many of the most recent episodes of a feed are listed.
+const MAX_EPISODES: usize = 50;
+
+/// Episodes listed per page.
+const PAGE_LEN: usize = 10;
+
+/// How many episodes may be queued with one command.
+const MAX_SELECTED: usize = 10;
+
+/// How often the position of a playing episode is saved.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Positions this close to the start are not worth resuming from.
+const MIN_POSITION: Duration = Duration::from_secs(30);
+
+/// Episodes stopped this close to their end count as listened to.
+const FINISHED_MARGIN: Duration = Duration::from_secs(60);
+
+/// How much of the show notes is shown when queueing an episode.
+const NOTES_PREVIEW_LEN: usize = 300;
+
+/// How long fetching a feed may take in all.
+const FEED_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The largest feed read; long-running shows list every episode ever made.
+const MAX_FEED_LEN: usize = 8 * 1024 * 1024;
+
+/// Where each guild stopped listening to episodes, by episode URL.
+pub struct Podcasts {
+    positions: RwLock<HashMap<u64, HashMap<String, u64>>>,
+}
+
+impl TypeMapKey for Podcasts {
+    type Value = Arc<Podcasts>;
+}
+
+impl Podcasts {
+    pub async fn load() -> Self {
+        Self {
+            positions: RwLock::new(storage::load(&podcasts_file()).await),
+        }
+    }
+
+    async fn position(&self, guild_id: GuildId, url: &str) -> Option<Duration> {
+        self.positions
+            .read()
+            .await
+            .get(&guild_id.0)
+            .and_then(|episodes| episodes.get(url))
+            .map(|secs| Duration::from_secs(*secs))
+    }
+
+    /// Saves where an episode was left, or forgets it once listened to.
+    async fn remember(&self, guild_id: GuildId, url: &str, position: Option<Duration>) {
+        let mut positions = self.positions.write().await;
+        let episodes = positions.entry(guild_id.0).or_default();
+        let changed = match position {
+            Some(position) => {
+                episodes.insert(url.to_string(), position.as_secs()) != Some(position.as_secs())
+            }
+            None => episodes.remove(url).is_some(),
+        };
+        if episodes.is_empty() {
+            positions.remove(&guild_id.0);
+        }
+
+        if changed {
+            if let Err(why) = storage::save(&podcasts_file(), &*positions).await {
+                println!("Err saving podcast positions: {:?}", why);
+            }
+        }
+    }
+}
+
+pub async fn podcasts(ctx: &Context) -> Arc<Podcasts> {
+    let data = ctx.data.read().await;
+    data.get::<Podcasts>()
+        .expect("Podcasts placed in at initialisation.")
+        .clone()
+}
+
+/// The show notes of a queued episode.
+struct ShowNotes;
+
+impl TypeMapKey for ShowNotes {
+    type Value = String;
+}
+
+struct Feed {
+    title: String,
+    image: Option<String>,
+    episodes: Vec<Episode>,
+}
+
+struct Episode {
+    title: String,
+    url: String,
+    published: Option<i64>,
+    duration: Option<Duration>,
+    notes: String,
+    image: Option<String>,
+}
+
+/// Parses iTunes durations, which are `HH:MM:SS`, `MM:SS` or seconds.
+fn parse_duration(text: &str) -> Option<Duration> {
+    text.trim()
+        .split(':')
+        .try_fold(0, |total: u64, part| {
+            part.parse::<u64>().ok().map(|value| total * 60 + value)
+        })
+        .map(Duration::from_secs)
+}
+
+/// Turns HTML show notes into plain text for embeds.
+fn plain_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut tag = None::<String>;
+    for c in html.chars() {
+        match (&mut tag, c) {
+            (None, '<') => tag = Some(String::new()),
+            (Some(name), '>') => {
+                let name = name.to_lowercase();
+                let name = name
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .trim_matches('/');
+                if ["br", "p", "li", "div"].contains(&name) {
+                    text.push('\n');
+                }
+                tag = None;
+            }
+            (Some(name), c) => name.push(c),
+            (None, c) => text.push(c),
+        }
+    }
+
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    let lines: Vec<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    lines.join("\n")
+}
+
+fn truncate(text: &str, len: usize) -> String {
+    match text.char_indices().nth(len) {
+        Some((index, _)) => format!("{}…", &text[..index]),
+        None => text.to_string(),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs / 3600 {
+        0 => format!("{}:{:02}", secs / 60, secs % 60),
+        h => format!("{}:{:02}:{:02}", h, secs / 60 % 60, secs % 60),
+    }
+}
+
+/// Downloads a feed, giving up on ones which are too slow or too large.
+async fn download(url: &str) -> Result<Vec<u8>, String> {
+    let response = net::client()
+        .get(url)
+        .timeout(FEED_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|why| format!("{:?}", why))?;
+    net::body(response, MAX_FEED_LEN).await
+}
+
+async fn fetch(url: &str) -> Option<Feed> {
+    let bytes = match download(url).await {
+        Ok(bytes) => bytes,
+        Err(why) => {
+            println!("Err fetching feed {}: {}", url, why);
+            return None;
+        }
+    };
+    let channel = match Channel::read_from(&bytes[..]) {
+        Ok(channel) => channel,
+        Err(why) => {
+            println!("Err parsing feed {}: {:?}", url, why);
+            return None;
+        }
+    };
+
+    let image = channel
+        .itunes_ext()
+        .and_then(|itunes| itunes.image())
+        .or_else(|| channel.image().map(|image| image.url()))
+        .map(String::from);
+    let episodes = channel
+        .items()
+        .iter()
+        .filter_map(|item| {
+            let itunes = item.itunes_ext();
+            Some(Episode {
+                title: item.title().unwrap_or("Untitled").to_string(),
+                url: item.enclosure()?.url().to_string(),
+                published: item
+                    .pub_date()
+                    .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+                    .map(|date| date.timestamp()),
+                duration: itunes
+                    .and_then(|itunes| itunes.duration())
+                    .and_then(parse_duration),
+                notes: plain_text(
+                    item.content()
+                        .or_else(|| item.description())
+                        .or_else(|| itunes.and_then(|itunes| itunes.summary()))
+                        .unwrap_or_default(),
+                ),
+                image: itunes.and_then(|itunes| itunes.image()).map(String::from),
+            })
+        })
+        .take(MAX_EPISODES)
+        .collect();
+
+    Some(Feed {
+        title: channel.title().to_string(),
+        image,
+        episodes,
+    })
+}
+
+fn episodes_embed(feed: &Feed, positions: &[Option<Duration>], page: usize) -> CreateEmbed {
+    let pages = feed.episodes.len().div_ceil(PAGE_LEN);
+    let mut list = String::new();
+    for (index, (episode, position)) in feed
+        .episodes
+        .iter()
+        .zip(positions)
+        .enumerate()
+        .skip((page - 1) * PAGE_LEN)
+        .take(PAGE_LEN)
+    {
+        list += &format!("`{}.` [{}]({})", index + 1, episode.title, episode.url);
+        if let Some(duration) = episode.duration {
+            list += &format!(" `{}`", format_duration(duration));
+        }
+        if let Some(published) = episode.published {
+            list += &format!(" <t:{}:d>", published);
+        }
+        if let Some(position) = position {
+            list += &format!(" · resumes at {}", format_duration(*position));
+        }
+        list += "\n";
+    }
+
+    let mut e = CreateEmbed::default();
+    e.title(&feed.title);
+    e.description(list.replace("@", "@\u{200B}"));
+    if let Some(image) = &feed.image {
+        e.thumbnail(image);
+    }
+    e.footer(|f| {
+        f.text(format!(
+            "page {}/{} · podcast <feed> play <numbers>",
+            page, pages
+        ))
+    });
+    e
+}
+
+/// Registers the events which resume an episode where the guild left it and
+/// keep its position saved while it plays.
+fn register_events(podcasts: Arc<Podcasts>, guild_id: GuildId, url: String, track: &TrackHandle) {
+    let _ = track.add_event(
+        Event::Track(TrackEvent::Play),
+        Resumer {
+            podcasts: podcasts.clone(),
+            guild_id,
+            url: url.clone(),
+        },
+    );
+    let bookmarker = Bookmarker {
+        podcasts,
+        guild_id,
+        url,
+    };
+    let _ = track.add_event(Event::Periodic(SAVE_INTERVAL, None), bookmarker.clone());
+    let _ = track.add_event(Event::Track(TrackEvent::End), bookmarker);
+}
+
+struct Resumer {
+    podcasts: Arc<Podcasts>,
+    guild_id: GuildId,
+    url: String,
+}
+
+#[async_trait]
+impl VoiceEventHandler for Resumer {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(tracks) = ctx {
+            if let Some(position) = self.podcasts.position(self.guild_id, &self.url).await {
+                for (_, track) in tracks.iter() {
+                    let _ = track.seek_time(position);
+                }
+            }
+        }
+
+        // Only the first start resumes; later ones are unpausing.
+        Some(Event::Cancel)
+    }
+}
+
+#[derive(Clone)]
+struct Bookmarker {
+    podcasts: Arc<Podcasts>,
+    guild_id: GuildId,
+    url: String,
+}
+
+#[async_trait]
+impl VoiceEventHandler for Bookmarker {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(tracks) = ctx {
+            for (state, track) in tracks.iter() {
+                let finished = state.playing == PlayMode::End
+                    || track
+                        .metadata()
+                        .duration
+                        .is_some_and(|duration| state.position + FINISHED_MARGIN >= duration);
+                if finished {
+                    self.podcasts.remember(self.guild_id, &self.url, None).await;
+                } else if state.position >= MIN_POSITION {
+                    self.podcasts
+                        .remember(self.guild_id, &self.url, Some(state.position))
+                        .await;
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Shows the notes of the episode playing now.
+async fn show_notes(ctx: &Context, msg: &Message, guild_id: GuildId) {
+    let session = session::get(ctx, guild_id).await;
+    let current = session.queue().await.and_then(|queue| queue.current());
+    let notes = match &current {
+        Some(track) => track.typemap().read().await.get::<ShowNotes>().cloned(),
+        None => None,
+    };
+
+    match (current, notes) {
+        (Some(track), Some(notes)) => {
+            let metadata = track.metadata();
+            check_msg(
+                msg.channel_id
+                    .send_message(&ctx.http, |m| {
+                        m.embed(|e| {
+                            e.title(metadata.title.clone().unwrap_or_default());
+                            e.description(truncate(&notes, 4000).replace("@", "@\u{200B}"));
+                            if let Some(thumbnail) = &metadata.thumbnail {
+                                e.thumbnail(thumbnail);
+                            }
+                            e
+                        })
+                    })
+                    .await,
+            );
+        }
+        _ => check_msg(
+            msg.channel_id
+                .say(&ctx.http, "No podcast episode is playing")
+                .await,
+        ),
+    }
+}
+
+#[command]
+#[aliases(pod)]
+#[only_in(guilds)]
+#[bucket = "queue"]
+async fn podcast(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+
+    let url = args.single::<String>().unwrap_or_default();
+    if url == "notes" {
+        show_notes(ctx, msg, guild_id).await;
+
+        return Ok(());
+    }
+    if !url.starts_with("http") {
+        check_msg(
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "Usage: podcast <feed url> [page], podcast <feed url> play <numbers>, podcast notes",
+                )
+                .await,
+        );
+
+        return Ok(());
+    }
+
+    let feed = match fetch(&url).await {
+        Some(feed) if !feed.episodes.is_empty() => feed,
+        Some(_) => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "That feed has no episodes")
+                    .await,
+            );
+
+            return Ok(());
+        }
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Couldn't read that podcast feed")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    let podcasts = podcasts(ctx).await;
+    let mut positions = Vec::with_capacity(feed.episodes.len());
+    for episode in &feed.episodes {
+        positions.push(podcasts.position(guild_id, &episode.url).await);
+    }
+
+    if args.current() != Some("play") {
+        let pages = feed.episodes.len().div_ceil(PAGE_LEN);
+        let page = args.single::<usize>().unwrap_or(1).clamp(1, pages);
+        check_msg(
+            msg.channel_id
+                .send_message(&ctx.http, |m| {
+                    m.set_embed(episodes_embed(&feed, &positions, page))
+                })
+                .await,
+        );
+
+        return Ok(());
+    }
+    args.advance();
+
+    let selected: Vec<usize> = args
+        .iter::<usize>()
+        .filter_map(Result::ok)
+        .filter(|number| (1..=feed.episodes.len()).contains(number))
+        .take(MAX_SELECTED)
+        .collect();
+    if selected.is_empty() {
+        check_msg(
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!("Pick episodes between 1 and {}", feed.episodes.len()),
+                )
+                .await,
+        );
+
+        return Ok(());
+    }
+
+    let session = session::get(ctx, guild_id).await;
+    if !session::join_author(ctx, msg, &session).await {
+        return Ok(());
+    }
+
+    let resolver = sources::resolver(ctx).await;
+    let mut e = CreateEmbed::default();
+    e.title(format!("Queued from {}", feed.title));
+    if let Some(image) = &feed.image {
+        e.thumbnail(image);
+    }
+
+    let requester = Requester::author(ctx, msg).await;
+    let _guard = session.lock().await;
+    let mut queued = 0;
+    for number in selected {
+        let episode = &feed.episodes[number - 1];
+        let metadata = Metadata {
+            title: Some(episode.title.clone()),
+            artist: Some(feed.title.clone()),
+            duration: episode.duration,
+            thumbnail: episode.image.clone().or_else(|| feed.image.clone()),
+            source_url: Some(episode.url.clone()),
+            ..Default::default()
+        };
+        let source = match resolver
+            .remote(episode.url.clone(), metadata, guild_id)
+            .await
+        {
+            Ok(source) => source,
+            Err(why) => {
+                println!("Err starting episode {}: {:?}", episode.url, why);
+                continue;
+            }
+        };
+        let track = match session.enqueue(source, &requester).await {
+            Ok(track) => track,
+            Err(why @ Refusal::Blocked(_)) | Err(why @ Refusal::Duplicate(_)) => {
+                check_msg(msg.reply(ctx, why.to_string()).await);
+                continue;
+            }
+            Err(why) => {
+                check_msg(msg.reply(ctx, why.to_string()).await);
+                break;
+            }
+        };
+        track
+            .typemap()
+            .write()
+            .await
+            .insert::<ShowNotes>(episode.notes.clone());
+        register_events(podcasts.clone(), guild_id, episode.url.clone(), &track);
+        queued += 1;
+
+        let mut value = truncate(&episode.notes, NOTES_PREVIEW_LEN);
+        if let Some(position) = positions[number - 1] {
+            value += &format!("\nResumes at {}", format_duration(position));
+        }
+        if value.is_empty() {
+            value = String::from("No show notes");
+        }
+        e.field(
+            format!("{}. {}", number, episode.title),
+            value.replace("@", "@\u{200B}"),
+            false,
+        );
+    }
+
+    if queued == 0 {
+        check_msg(
+            msg.channel_id
+                .say(&ctx.http, "Couldn't queue any of those episodes")
+                .await,
+        );
+
+        return Ok(());
+    }
+    check_msg(
+        msg.channel_id
+            .send_message(&ctx.http, |m| m.set_embed(e))
+            .await,
+    );
+
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serenity::{
+    async_trait,
+    client::{bridge::gateway::ShardManager, Context},
+    model::gateway::Activity,
+    prelude::{Mutex, TypeMapKey},
+};
+use songbird::{
+    tracks::PlayMode, Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+
+use crate::{radio, session::Sessions};
+
+/// How often the summary shown while playing in several guilds changes.
+const ROTATE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Discord cuts activity names at 128 characters.
+const MAX_NAME_LEN: usize = 128;
+
+/// Keeps the bot presence in line with what it is playing.
+pub struct Presence {
+    shard_manager: Arc<Mutex<ShardManager>>,
+    sessions: Arc<Sessions>,
+    rotation: AtomicUsize,
+    /// Shown while nothing plays, configured through `IDLE_STATUS`.
+    idle: Option<String>,
+}
+
+impl TypeMapKey for Presence {
+    type Value = Arc<Presence>;
+}
+
+impl Presence {
+    pub fn new(shard_manager: Arc<Mutex<ShardManager>>, sessions: Arc<Sessions>) -> Self {
+        Self {
+            shard_manager,
+            sessions,
+            rotation: AtomicUsize::new(0),
+            idle: env::var("IDLE_STATUS")
+                .ok()
+                .filter(|status| !status.is_empty()),
+        }
+    }
+
+    async fn activity(&self) -> Option<Activity> {
+        let mut playing = Vec::new();
+        let mut queued = 0;
+        for session in self.sessions.all().await {
+            let queue = match session.queue().await {
+                Some(queue) => queue,
+                None => continue,
+            };
+            let current = match queue.current() {
+                Some(current) => current,
+                None => continue,
+            };
+            if let Ok(info) = current.get_info().await {
+                if info.playing == PlayMode::Play {
+                    playing.push(Some(radio::title(&current).await));
+                    queued += queue.len();
+                }
+            }
+        }
+
+        match playing.len() {
+            0 => self.idle.as_deref().map(Activity::playing),
+            1 => {
+                let title = playing[0].as_deref().unwrap_or("None");
+                Some(Activity::listening(
+                    title.chars().take(MAX_NAME_LEN).collect::<String>(),
+                ))
+            }
+            servers => match self.rotation.load(Ordering::Relaxed) % 2 {
+                0 => Some(Activity::playing(format!("music in {} servers", servers))),
+                _ => Some(Activity::listening(format!("{} queued songs", queued))),
+            },
+        }
+    }
+
+    /// Sets the presence on every shard this process runs.
+    pub async fn update(&self) {
+        let activity = self.activity().await;
+
+        let manager = self.shard_manager.lock().await;
+        for runner in manager.runners.lock().await.values() {
+            runner.runner_tx.set_activity(activity.clone());
+        }
+    }
+
+    /// Refreshes the presence periodically, rotating the summary.
+    pub async fn rotate(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(ROTATE_INTERVAL).await;
+            self.rotation.fetch_add(1, Ordering::Relaxed);
+            self.update().await;
+        }
+    }
+}
+
+pub async fn presence(ctx: &Context) -> Arc<Presence> {
+    let data = ctx.data.read().await;
+    data.get::<Presence>()
+        .expect("Presence placed in at initialisation.")
+        .clone()
+}
+
+pub fn register_events(presence: Arc<Presence>, handler: &mut Call) {
+    for event in [TrackEvent::Play, TrackEvent::Pause, TrackEvent::End] {
+        handler.add_global_event(
+            Event::Track(event),
+            PresenceUpdater {
+                presence: presence.clone(),
+            },
+        );
+    }
+}
+
+struct PresenceUpdater {
+    presence: Arc<Presence>,
+}
+
+#[async_trait]
+impl VoiceEventHandler for PresenceUpdater {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        self.presence.update().await;
+
+        None
+    }
+}
@@ -0,0 +1,359 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use reqwest::{header::CONTENT_TYPE, Client, RequestBuilder, Response};
+use serenity::{
+    async_trait,
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::channel::Message,
+    prelude::TypeMapKey,
+};
+use songbird::{
+    tracks::TrackHandle, Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+
+use crate::{
+    check_msg, duplicates, net,
+    session::{self, Requester},
+    sources, storage, ytdl,
+};
+
+const STATIONS_FILE: &str = "stations.json";
+
+/// Stations offered until the data directory has its own `stations.json`.
+const DEFAULT_STATIONS: [(&str, &str); 3] = [
+    ("groovesalad", "https://ice1.somafm.com/groovesalad-128-mp3"),
+    ("dronezone", "https://ice1.somafm.com/dronezone-128-mp3"),
+    ("paradise", "https://stream.radioparadise.com/mp3-128"),
+];
+
+/// The most of a playlist file read when looking for the stream in it.
+const MAX_PLAYLIST_LEN: usize = 64 * 1024;
+
+/// How long probing a URL may take, playlist files included.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a followed stream may send nothing before it is given up on.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Named radio streams for `!radio`, loaded from `stations.json` as a map
+/// of names to URLs.
+pub struct Stations(BTreeMap<String, String>);
+
+impl TypeMapKey for Stations {
+    type Value = Arc<Stations>;
+}
+
+impl Stations {
+    pub async fn load() -> Self {
+        let stations: BTreeMap<String, String> = storage::load(STATIONS_FILE).await;
+        if stations.is_empty() {
+            return Self(
+                DEFAULT_STATIONS
+                    .iter()
+                    .map(|(name, url)| (name.to_string(), url.to_string()))
+                    .collect(),
+            );
+        }
+        Self(
+            stations
+                .into_iter()
+                .map(|(name, url)| (name.to_lowercase(), url))
+                .collect(),
+        )
+    }
+}
+
+/// A live stream found behind a URL.
+#[derive(Clone, Debug)]
+pub struct Stream {
+    /// What ffmpeg plays, which differs from the URL for `.pls` and `.m3u` files.
+    pub url: String,
+    /// The station name from the `icy-name` header.
+    pub name: Option<String>,
+}
+
+fn header(response: &Response, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+fn request(client: &Client, url: &str) -> RequestBuilder {
+    client.get(url).header("Icy-MetaData", "1")
+}
+
+async fn send(request: RequestBuilder) -> Option<Response> {
+    request
+        .send()
+        .await
+        .ok()
+        .filter(|response| response.status().is_success())
+}
+
+/// The first stream listed in an `.m3u` or `.pls` playlist.
+fn playlist_entry(text: &str) -> Option<String> {
+    text.lines()
+        .map(str::trim)
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) if key.to_lowercase().starts_with("file") => value,
+            _ => line,
+        })
+        .find(|line| line.starts_with("http"))
+        .map(String::from)
+}
+
+/// Checks whether a URL is a radio or other HTTP audio stream, rather than
+/// something for yt-dlp, by looking at the response headers.
+pub async fn probe(client: &Client, url: &str) -> Option<Stream> {
+    if ytdl::video_id(url).is_some() {
+        return None;
+    }
+
+    let mut url = url.to_string();
+    // A playlist file may point at another one, but not forever.
+    for _ in 0..2 {
+        let response = send(request(client, &url).timeout(PROBE_TIMEOUT)).await?;
+        let content_type = header(&response, CONTENT_TYPE.as_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let path = url.split('?').next().unwrap_or_default().to_lowercase();
+
+        let playlist = content_type.contains("mpegurl")
+            || content_type.contains("scpls")
+            || [".m3u", ".m3u8", ".pls"]
+                .iter()
+                .any(|extension| path.ends_with(extension));
+        if playlist {
+            let body = net::body(response, MAX_PLAYLIST_LEN).await.ok()?;
+            let text = String::from_utf8_lossy(&body);
+            // HLS is a playlist of segments, which ffmpeg follows by itself.
+            if text.contains("#EXT-X-") {
+                return Some(Stream { url, name: None });
+            }
+            url = playlist_entry(&text)?;
+            continue;
+        }
+
+        let name = header(&response, "icy-name");
+        let icy = name.is_some() || header(&response, "icy-metaint").is_some();
+        // Audio files, like podcast episodes, have a length and can be seeked.
+        let live = response.content_length().is_none()
+            && (content_type.starts_with("audio/") || content_type == "application/ogg");
+        return (icy || live).then_some(Stream { url, name });
+    }
+
+    None
+}
+
+/// The song currently on air, read from a stream's ICY metadata.
+struct LiveTitle;
+
+impl TypeMapKey for LiveTitle {
+    type Value = String;
+}
+
+/// Marks a track playing a live stream found by [`probe`], which it keeps so
+/// the stream's titles can be followed without probing again.
+pub struct Live;
+
+impl TypeMapKey for Live {
+    type Value = Stream;
+}
+
+/// Marks a track whose ICY metadata is already being read.
+struct Watched;
+
+impl TypeMapKey for Watched {
+    type Value = ();
+}
+
+/// The title of a track, with the song on air for radio streams.
+pub async fn title(track: &TrackHandle) -> String {
+    let title = track
+        .metadata()
+        .title
+        .clone()
+        .unwrap_or(String::from("None"));
+    match track.typemap().read().await.get::<LiveTitle>() {
+        Some(live) => format!("{} - {}", title, live),
+        None => title,
+    }
+}
+
+/// Takes the `StreamTitle` out of an ICY metadata block.
+fn stream_title(metadata: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(metadata);
+    let start = text.find("StreamTitle='")? + "StreamTitle='".len();
+    let end = text[start..].find("';").map(|end| start + end)?;
+    let title = text[start..end].trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+/// Follows the ICY metadata of a stream on a connection of its own until the
+/// track ends, keeping its live title up to date.
+async fn watch(client: Client, track: TrackHandle, url: String) {
+    let mut response = match send(request(&client, &url)).await {
+        Some(response) => response,
+        None => return,
+    };
+    let interval: usize = match header(&response, "icy-metaint").and_then(|n| n.parse().ok()) {
+        Some(interval) if interval > 0 => interval,
+        _ => return,
+    };
+
+    // The stream alternates `interval` bytes of audio with a length byte
+    // counting 16 byte blocks and that much metadata.
+    let mut audio_left = interval;
+    let mut metadata_len: Option<usize> = None;
+    let mut metadata = Vec::new();
+    while let Ok(Ok(Some(chunk))) = tokio::time::timeout(READ_TIMEOUT, response.chunk()).await {
+        let mut chunk = &chunk[..];
+        while !chunk.is_empty() {
+            if audio_left > 0 {
+                let skip = audio_left.min(chunk.len());
+                audio_left -= skip;
+                chunk = &chunk[skip..];
+                continue;
+            }
+
+            let len = match metadata_len {
+                Some(len) => len,
+                None => {
+                    let len = usize::from(chunk[0]) * 16;
+                    chunk = &chunk[1..];
+                    metadata_len = Some(len);
+                    len
+                }
+            };
+            let take = (len - metadata.len()).min(chunk.len());
+            metadata.extend_from_slice(&chunk[..take]);
+            chunk = &chunk[take..];
+            if metadata.len() < len {
+                continue;
+            }
+
+            if track.get_info().await.is_err() {
+                return;
+            }
+            if let Some(title) = stream_title(&metadata) {
+                track.typemap().write().await.insert::<LiveTitle>(title);
+            }
+            metadata.clear();
+            metadata_len = None;
+            audio_left = interval;
+        }
+    }
+}
+
+/// Registers the event which follows the live titles of radio streams.
+pub fn register_events(client: Client, handler: &mut Call) {
+    handler.add_global_event(Event::Track(TrackEvent::Play), TitleWatcher { client });
+}
+
+struct TitleWatcher {
+    client: Client,
+}
+
+#[async_trait]
+impl VoiceEventHandler for TitleWatcher {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(tracks) = ctx {
+            for (_, track) in tracks.iter() {
+                let stream = {
+                    let mut typemap = track.typemap().write().await;
+                    let stream = match typemap.get::<Live>() {
+                        Some(stream) if !typemap.contains_key::<Watched>() => stream.clone(),
+                        _ => continue,
+                    };
+                    typemap.insert::<Watched>(());
+                    stream
+                };
+
+                tokio::spawn(watch(self.client.clone(), (*track).clone(), stream.url));
+            }
+        }
+
+        None
+    }
+}
+
+#[command]
+#[aliases(fm)]
+#[only_in(guilds)]
+#[bucket = "queue"]
+async fn radio(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let stations = {
+        let data = ctx.data.read().await;
+        data.get::<Stations>()
+            .expect("Stations placed in at initialisation.")
+            .clone()
+    };
+
+    let name = args.rest().trim().to_lowercase();
+    let url = match stations.0.get(&name) {
+        Some(url) => url.clone(),
+        None => {
+            let names: Vec<&str> = stations.0.keys().map(String::as_str).collect();
+            let reply = if name.is_empty() {
+                format!("Stations: {}", names.join(", "))
+            } else {
+                format!("No station named **{}**, try {}", name, names.join(", "))
+            };
+            check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+            return Ok(());
+        }
+    };
+
+    let session = session::get(ctx, guild_id).await;
+    if !session::join_author(ctx, msg, &session).await
+        || !duplicates::check(ctx, msg, &session, &url).await
+    {
+        return Ok(());
+    }
+
+    let source = match sources::resolver(ctx).await.resolve(url, guild_id).await {
+        Ok(source) => source,
+        Err(why) => {
+            println!("Err starting radio: {:?}", why);
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, format!("Err starting source: {:?}", why))
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    let requester = Requester::author(ctx, msg).await;
+    let _guard = session.lock().await;
+    let reply = match (
+        session.enqueue(source, &requester).await,
+        session.queue().await,
+    ) {
+        (Ok(track), Some(queue)) if queue.len() > 1 => format!(
+            "Added **{}** to queue: position {}",
+            title(&track).await,
+            queue.len() - 1
+        ),
+        (Ok(track), _) => format!("Tuned in to **{}**", title(&track).await),
+        (Err(why), _) => why.to_string(),
+    };
+    check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+    Ok(())
+}
+
+/// Formats the length of a track, which live streams do not have.
+pub async fn length(track: &TrackHandle) -> String {
+    if track.typemap().read().await.contains_key::<Live>() {
+        return String::from("LIVE");
+    }
+    let duration = track.metadata().duration.unwrap_or_default();
+    format!("{}:{:02}", duration.as_secs() / 60, duration.as_secs() % 60)
+}
@@ -0,0 +1,590 @@
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use chrono::{
+    DateTime, Datelike, Duration as Days, NaiveDate, NaiveTime, TimeZone, Timelike, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{
+        channel::{Channel, ChannelType, Message},
+        id::{ChannelId, GuildId, UserId},
+    },
+    prelude::{Mutex, TypeMapKey},
+};
+
+use crate::{
+    check_msg, permissions, playlists,
+    session::{self, Requester},
+    shards, sources, storage, ytdl,
+};
+
+/// How often due schedules are looked for.
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How late a schedule may still start, like after a restart; later runs are skipped.
+const GRACE_SECS: i64 = 10 * 60;
+
+/// The most tracks a scheduled YouTube playlist queues, like `!playlist`.
+const MAX_PLAYLIST_TRACKS: usize = 50;
+
+fn schedules_file() -> String {
+    format!("schedules{}.json", shards::config().file_suffix())
+}
+
+/// Timezone for schedules which do not name one, configured through `TIMEZONE`.
+fn default_timezone() -> Tz {
+    env::var("TIMEZONE")
+        .ok()
+        .and_then(|name| name.parse().ok())
+        .unwrap_or(Tz::UTC)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Repeat {
+    Once,
+    Daily,
+    Weekly,
+}
+
+/// A session the bot starts on its own, persisted until it has run for the
+/// last time or is removed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Schedule {
+    id: u32,
+    guild_id: u64,
+    voice_channel: u64,
+    text_channel: u64,
+    creator: u64,
+    /// A URL or the name of a saved playlist.
+    source: String,
+    repeat: Repeat,
+    /// Days from Monday, for weekly schedules.
+    weekday: Option<u32>,
+    hour: u32,
+    minute: u32,
+    timezone: String,
+    /// Unix time of the next run.
+    next: i64,
+}
+
+impl Schedule {
+    fn timezone(&self) -> Tz {
+        self.timezone.parse().unwrap_or(Tz::UTC)
+    }
+
+    fn describe(&self) -> String {
+        let time = format!("{:02}:{:02} {}", self.hour, self.minute, self.timezone);
+        let when = match (self.repeat, self.weekday) {
+            (Repeat::Weekly, Some(weekday)) => {
+                format!("every {} {}", weekday_name(weekday), time)
+            }
+            (Repeat::Daily, _) => format!("every day {}", time),
+            _ => format!("once, {}", time),
+        };
+        format!(
+            "**#{}** {} in <#{}>: {}, next <t:{}:F>",
+            self.id, when, self.voice_channel, self.source, self.next
+        )
+    }
+
+    /// When the schedule runs next after `after`, if it runs again at all.
+    fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self.repeat {
+            Repeat::Once => None,
+            repeat => next_time(
+                self.timezone(),
+                after,
+                None,
+                self.weekday.filter(|_| repeat == Repeat::Weekly),
+                self.hour,
+                self.minute,
+            ),
+        }
+    }
+}
+
+fn weekday_name(days_from_monday: u32) -> &'static str {
+    [
+        "Monday",
+        "Tuesday",
+        "Wednesday",
+        "Thursday",
+        "Friday",
+        "Saturday",
+        "Sunday",
+    ][days_from_monday as usize % 7]
+}
+
+/// The first time after `after` on the given date or weekday at the given
+/// local time, skipping days where the time falls into a DST gap.
+fn next_time(
+    timezone: Tz,
+    after: DateTime<Utc>,
+    date: Option<NaiveDate>,
+    weekday: Option<u32>,
+    hour: u32,
+    minute: u32,
+) -> Option<DateTime<Utc>> {
+    let time = NaiveTime::from_hms_opt(hour, minute, 0)?;
+    let dates: Vec<NaiveDate> = match date {
+        Some(date) => vec![date],
+        None => {
+            let today = after.with_timezone(&timezone).date_naive();
+            (0..=7).map(|days| today + Days::days(days)).collect()
+        }
+    };
+
+    dates
+        .into_iter()
+        .filter(|date| {
+            weekday.is_none_or(|weekday| date.weekday().num_days_from_monday() == weekday)
+        })
+        .filter_map(|date| {
+            timezone
+                .from_local_datetime(&date.and_time(time))
+                .earliest()
+        })
+        .map(|time| time.with_timezone(&Utc))
+        .find(|time| *time > after)
+}
+
+/// What `!schedule` understood of its time arguments.
+#[derive(Default)]
+struct When {
+    every: bool,
+    daily: bool,
+    weekday: Option<u32>,
+    date: Option<NaiveDate>,
+    /// Days from today, for `today` and `tomorrow` in the schedule's timezone.
+    days: Option<i64>,
+    time: Option<(u32, u32)>,
+    timezone: Option<Tz>,
+}
+
+impl When {
+    /// Takes one word of the time, returning `false` when it is not part of it.
+    fn take(&mut self, word: &str) -> bool {
+        let lower = word.to_lowercase();
+        match lower.as_str() {
+            "every" => self.every = true,
+            "day" | "daily" => self.daily = true,
+            "today" => self.days = Some(0),
+            "tomorrow" => self.days = Some(1),
+            _ => {
+                if let Ok(weekday) = lower.parse::<Weekday>() {
+                    self.weekday = Some(weekday.num_days_from_monday());
+                } else if let Ok(date) = NaiveDate::parse_from_str(&lower, "%Y-%m-%d") {
+                    self.date = Some(date);
+                } else if let Ok(time) = NaiveTime::parse_from_str(&lower, "%H:%M") {
+                    self.time = Some((time.hour(), time.minute()));
+                } else if let Ok(timezone) = word.parse::<Tz>() {
+                    self.timezone = Some(timezone);
+                } else {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn date(&self, timezone: Tz) -> Option<NaiveDate> {
+        self.date.or_else(|| {
+            let today = Utc::now().with_timezone(&timezone).date_naive();
+            self.days.map(|days| today + Days::days(days))
+        })
+    }
+
+    fn repeat(&self) -> Option<Repeat> {
+        let dated = self.date.is_some() || self.days.is_some();
+        match (self.every || self.daily, self.weekday, dated) {
+            (true, Some(_), false) => Some(Repeat::Weekly),
+            (true, None, false) => Some(Repeat::Daily),
+            (false, _, _) => Some(Repeat::Once),
+            _ => None,
+        }
+    }
+}
+
+/// Scheduled sessions of the guilds this process runs.
+pub struct Schedules {
+    schedules: Mutex<Vec<Schedule>>,
+    started: AtomicBool,
+}
+
+impl TypeMapKey for Schedules {
+    type Value = Arc<Schedules>;
+}
+
+impl Schedules {
+    pub async fn load() -> Self {
+        Self {
+            schedules: Mutex::new(storage::load(&schedules_file()).await),
+            started: AtomicBool::new(false),
+        }
+    }
+
+    async fn save(&self, schedules: &[Schedule]) {
+        if let Err(why) = storage::save(&schedules_file(), schedules).await {
+            println!("Err saving schedules: {:?}", why);
+        }
+    }
+
+    /// Runs due schedules until the bot shuts down; only the first call starts
+    /// the loop, as `ready` fires again on reconnects.
+    pub fn start(self: &Arc<Self>, ctx: &Context) {
+        if self.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let schedules = self.clone();
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                // Runs resolve their tracks, which must not hold up other schedules.
+                for schedule in schedules.due().await {
+                    let ctx = ctx.clone();
+                    tokio::spawn(async move { run(&ctx, &schedule).await });
+                }
+            }
+        });
+    }
+
+    /// Takes the schedules which are due now, moving recurring ones to their
+    /// next run and dropping the ones which have run for the last time.
+    async fn due(&self) -> Vec<Schedule> {
+        let now = Utc::now();
+        let mut schedules = self.schedules.lock().await;
+
+        let mut due = Vec::new();
+        let mut changed = false;
+        schedules.retain_mut(|schedule| {
+            if schedule.next > now.timestamp() {
+                return true;
+            }
+            changed = true;
+            if now.timestamp() - schedule.next <= GRACE_SECS {
+                due.push(schedule.clone());
+            } else {
+                println!("Skipping missed schedule #{}", schedule.id);
+            }
+            match schedule.next_after(now) {
+                Some(next) => {
+                    schedule.next = next.timestamp();
+                    true
+                }
+                None => false,
+            }
+        });
+
+        if changed {
+            self.save(&schedules).await;
+        }
+        due
+    }
+}
+
+pub async fn schedules(ctx: &Context) -> Arc<Schedules> {
+    let data = ctx.data.read().await;
+    data.get::<Schedules>()
+        .expect("Schedules placed in at initialisation.")
+        .clone()
+}
+
+/// The URLs a schedule plays, expanding YouTube playlists and saved playlists.
+async fn urls(ctx: &Context, schedule: &Schedule) -> Vec<String> {
+    if schedule.source.starts_with("http") {
+        if !schedule.source.contains("list=") {
+            return vec![schedule.source.clone()];
+        }
+        return match ytdl::flat_playlist(&schedule.source).await {
+            Ok(mut urls) => {
+                urls.truncate(MAX_PLAYLIST_TRACKS);
+                urls
+            }
+            Err(why) => {
+                println!("Err listing playlist: {:?}", why);
+                Vec::new()
+            }
+        };
+    }
+
+    let playlist = playlists::find(
+        ctx,
+        UserId(schedule.creator),
+        GuildId(schedule.guild_id),
+        &schedule.source,
+    )
+    .await;
+    playlist
+        .map(|playlist| playlist.tracks.into_iter().map(|track| track.url).collect())
+        .unwrap_or_default()
+}
+
+/// Joins the voice channel of a schedule and queues its tracks.
+async fn run(ctx: &Context, schedule: &Schedule) {
+    let guild_id = GuildId(schedule.guild_id);
+    let text_channel = ChannelId(schedule.text_channel);
+    // With several processes, each runs only the schedules of its own guilds.
+    if ctx.cache.guild(guild_id).is_none() {
+        return;
+    }
+
+    let urls = urls(ctx, schedule).await;
+    if urls.is_empty() {
+        check_msg(
+            text_channel
+                .say(
+                    &ctx.http,
+                    format!("Scheduled **#{}** has nothing to play", schedule.id),
+                )
+                .await,
+        );
+        return;
+    }
+
+    let session = session::get(ctx, guild_id).await;
+    if let Err(why) = session
+        .connect(ctx, ChannelId(schedule.voice_channel))
+        .await
+    {
+        println!("Err starting schedule #{}: {:?}", schedule.id, why);
+        return;
+    }
+    session.state.write().await.channel = Some(text_channel);
+
+    let sources = sources::resolver(ctx)
+        .await
+        .resolve_all(urls, guild_id)
+        .await;
+
+    let requester = Requester::member(ctx, guild_id, UserId(schedule.creator)).await;
+    let _guard = session.lock().await;
+    let mut queued = 0;
+    for (url, source) in sources {
+        match source {
+            Ok(source) => match session.enqueue(source, &requester).await {
+                Ok(_) => queued += 1,
+                Err(why) => println!("Err queueing scheduled source {}: {}", url, why),
+            },
+            Err(why) => println!("Err starting scheduled source {}: {:?}", url, why),
+        }
+    }
+
+    check_msg(
+        text_channel
+            .say(
+                &ctx.http,
+                format!(
+                    "Scheduled session **#{}** started with **{}** Songs",
+                    schedule.id, queued
+                ),
+            )
+            .await,
+    );
+}
+
+/// Finds a voice channel of the guild by mention, ID or name.
+fn voice_channel(ctx: &Context, guild_id: GuildId, arg: &str) -> Option<ChannelId> {
+    let guild = ctx.cache.guild(guild_id)?;
+    let id = arg
+        .trim_start_matches("<#")
+        .trim_end_matches('>')
+        .parse::<u64>()
+        .ok();
+
+    guild
+        .channels
+        .values()
+        .filter_map(|channel| match channel {
+            Channel::Guild(channel) => Some(channel),
+            _ => None,
+        })
+        .filter(|channel| matches!(channel.kind, ChannelType::Voice | ChannelType::Stage))
+        .find(|channel| match id {
+            Some(id) => channel.id.0 == id,
+            None => channel.name.eq_ignore_ascii_case(arg),
+        })
+        .map(|channel| channel.id)
+}
+
+const USAGE: &str = "Usage: schedule <time> <voice channel> <playlist or URL>, \
+    like `schedule every friday 21:00 Europe/Berlin #music https://...`";
+
+#[command]
+#[only_in(guilds)]
+async fn schedule(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    if !permissions::require_manager(ctx, msg).await {
+        return Ok(());
+    }
+
+    let mut when = When::default();
+    while let Some(word) = args.current() {
+        if !when.take(word) {
+            break;
+        }
+        args.advance();
+    }
+
+    let channel = args
+        .single_quoted::<String>()
+        .ok()
+        .and_then(|arg| voice_channel(ctx, guild_id, &arg));
+    let source = args.rest().trim().to_string();
+    let (repeat, (hour, minute), channel) = match (when.repeat(), when.time, channel) {
+        (Some(repeat), Some(time), Some(channel)) if !source.is_empty() => (repeat, time, channel),
+        _ => {
+            check_msg(msg.channel_id.say(&ctx.http, USAGE).await);
+
+            return Ok(());
+        }
+    };
+
+    if !source.starts_with("http")
+        && playlists::find(ctx, msg.author.id, guild_id, &source)
+            .await
+            .is_none()
+    {
+        check_msg(
+            msg.channel_id
+                .say(&ctx.http, format!("No playlist named **{}**", source))
+                .await,
+        );
+
+        return Ok(());
+    }
+
+    let timezone = when.timezone.unwrap_or_else(default_timezone);
+    let date = when.date(timezone);
+    let weekday = when.weekday.filter(|_| date.is_none());
+    let next = match next_time(timezone, Utc::now(), date, weekday, hour, minute) {
+        Some(next) => next,
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "That time is in the past")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    let schedules = schedules(ctx).await;
+    let mut entries = schedules.schedules.lock().await;
+    let schedule = Schedule {
+        id: entries
+            .iter()
+            .map(|schedule| schedule.id)
+            .max()
+            .unwrap_or(0)
+            + 1,
+        guild_id: guild_id.0,
+        voice_channel: channel.0,
+        text_channel: msg.channel_id.0,
+        creator: msg.author.id.0,
+        source,
+        repeat,
+        weekday: weekday.filter(|_| repeat == Repeat::Weekly),
+        hour,
+        minute,
+        timezone: timezone.name().to_string(),
+        next: next.timestamp(),
+    };
+    let reply = format!("Scheduled {}", schedule.describe());
+    entries.push(schedule);
+    schedules.save(&entries).await;
+    drop(entries);
+
+    check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+    Ok(())
+}
+
+#[command("schedules")]
+#[only_in(guilds)]
+async fn list_schedules(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+
+    let lines: Vec<String> = schedules(ctx)
+        .await
+        .schedules
+        .lock()
+        .await
+        .iter()
+        .filter(|schedule| schedule.guild_id == guild_id.0)
+        .map(Schedule::describe)
+        .collect();
+
+    let reply = if lines.is_empty() {
+        String::from("No scheduled sessions")
+    } else {
+        lines.join("\n")
+    };
+    check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn unschedule(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let id = match args.single::<String>() {
+        Ok(id) => id.trim_start_matches('#').parse::<u32>().ok(),
+        Err(_) => None,
+    };
+    let id = match id {
+        Some(id) => id,
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Usage: unschedule <id>")
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    let schedules = schedules(ctx).await;
+    let creator = schedules
+        .schedules
+        .lock()
+        .await
+        .iter()
+        .find(|schedule| schedule.id == id && schedule.guild_id == guild_id.0)
+        .map(|schedule| schedule.creator);
+
+    // Anyone may remove their own schedules, managers everyone's.
+    let allowed = match creator {
+        Some(creator) => creator == msg.author.id.0 || permissions::is_manager(ctx, msg).await,
+        None => false,
+    };
+
+    let reply = match creator {
+        None => "No such schedule",
+        Some(_) if !allowed => "Only its creator or a server manager can remove that schedule",
+        Some(_) => {
+            let mut entries = schedules.schedules.lock().await;
+            entries.retain(|schedule| schedule.id != id);
+            schedules.save(&entries).await;
+            "Schedule removed"
+        }
+    };
+    check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+    Ok(())
+}
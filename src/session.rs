@@ -0,0 +1,975 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
+
+use serenity::{
+    async_trait,
+    client::Context,
+    http::CacheHttp,
+    model::{
+        channel::Message,
+        guild::Member,
+        id::{ChannelId, GuildId, MessageId, RoleId, UserId},
+    },
+    prelude::{Mutex, RwLock, TypeMapKey},
+};
+use songbird::{
+    error::JoinError,
+    input::{Input, Metadata},
+    tracks::{TrackHandle, TrackQueue},
+    Call, Songbird,
+};
+use tokio::sync::MutexGuard;
+
+use crate::{
+    api, autoplay,
+    blocklist::Blocklists,
+    cache, check_msg,
+    duplicates::{self, Policy as DuplicatePolicy},
+    history,
+    history::HistoryEntry,
+    limits::{self, Cooldowns},
+    loudness, metrics, net, permissions, player, presence, radio,
+    settings::{self, Settings},
+    sleep::{format_duration, SleepTimer},
+    sources::{self, Resolver},
+    stay,
+    transitions::{self, FadeEnd},
+};
+
+/// Runtime state of a guild which outlives its voice connection.
+#[derive(Default)]
+pub struct SessionState {
+    /// The player message, which is edited whenever the track changes.
+    pub player: Option<(ChannelId, MessageId)>,
+    /// Played tracks, oldest first.
+    pub history: VecDeque<HistoryEntry>,
+    /// The history entry `previous` last went back to, counted from the oldest.
+    pub rewind: Option<usize>,
+    /// The text channel the bot was last summoned from.
+    pub channel: Option<ChannelId>,
+    /// When set, the bot stops and leaves on its own.
+    pub sleep: Option<SleepTimer>,
+    /// The track fading out before a skip or stop, and what happens once it
+    /// is silent.
+    pub fade: Option<(TrackHandle, FadeEnd)>,
+    /// The next track while it crossfades in under the current one.
+    pub overlap: Option<TrackHandle>,
+    /// The volume set from the player, which tracks queued later play at too.
+    pub volume: Option<f32>,
+}
+
+/// Who a track is queued for, which decides the checks it has to pass.
+#[derive(Clone, Debug)]
+pub enum Requester {
+    /// A member of the guild, held to the queue limits unless they manage it.
+    Member {
+        id: UserId,
+        roles: Vec<RoleId>,
+        manager: bool,
+    },
+    /// The bot itself, for autoplay and 24/7 refills.
+    Bot,
+}
+
+impl Requester {
+    pub fn from_member(ctx: &impl CacheHttp, member: &Member) -> Self {
+        Requester::Member {
+            id: member.user.id,
+            roles: member.roles.clone(),
+            manager: ctx
+                .cache()
+                .is_some_and(|cache| permissions::can_manage(cache, member)),
+        }
+    }
+
+    /// The author of a command.
+    pub async fn author(ctx: &Context, msg: &Message) -> Self {
+        match msg.member(ctx).await {
+            Ok(member) => Self::from_member(&ctx, &member),
+            Err(_) => Requester::Member {
+                id: msg.author.id,
+                roles: Vec::new(),
+                manager: false,
+            },
+        }
+    }
+
+    /// A member of the guild who is not around, like the creator of a schedule.
+    pub async fn member(ctx: impl CacheHttp, guild_id: GuildId, user_id: UserId) -> Self {
+        match guild_id.member(&ctx, user_id).await {
+            Ok(member) => Self::from_member(&ctx, &member),
+            Err(_) => Requester::Member {
+                id: user_id,
+                roles: Vec::new(),
+                manager: false,
+            },
+        }
+    }
+
+    pub fn id(&self) -> Option<UserId> {
+        match self {
+            Requester::Member { id, .. } => Some(*id),
+            Requester::Bot => None,
+        }
+    }
+}
+
+/// Why a track was not added to the queue.
+#[derive(Debug)]
+pub enum Refusal {
+    NotConnected,
+    /// The requester is on the guild's blocklist.
+    Requester(String),
+    /// The track is on the guild's blocklist.
+    Blocked(String),
+    /// The track is queued already and the guild rejects duplicates.
+    Duplicate(String),
+    /// The track would take the requester over their queue limits.
+    Limit(String),
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::NotConnected => f.write_str("Not in a voice channel to play in"),
+            Refusal::Requester(why)
+            | Refusal::Blocked(why)
+            | Refusal::Duplicate(why)
+            | Refusal::Limit(why) => f.write_str(why),
+        }
+    }
+}
+
+/// The voice connection a session plays through, which is songbird's call
+/// and queue in the bot and a fake in tests.
+#[async_trait]
+pub trait Voice: Send + Sync + 'static {
+    /// Adds a track to the end of the queue.
+    async fn enqueue(&self, source: Input) -> TrackHandle;
+
+    /// The queued tracks, the current one first.
+    fn tracks(&self) -> Vec<TrackHandle>;
+
+    /// Ends `count` tracks starting at `from`, or right after it when it has
+    /// left the queue already, and plays the next one.
+    fn skip(&self, from: &TrackHandle, count: usize);
+
+    /// Ends the current track and clears the queue.
+    fn stop(&self);
+}
+
+pub struct Connection {
+    handler: Arc<Mutex<Call>>,
+    queue: TrackQueue,
+}
+
+#[async_trait]
+impl Voice for Connection {
+    async fn enqueue(&self, source: Input) -> TrackHandle {
+        self.handler.lock().await.enqueue_source(source)
+    }
+
+    fn tracks(&self) -> Vec<TrackHandle> {
+        self.queue.current_queue()
+    }
+
+    fn skip(&self, from: &TrackHandle, count: usize) {
+        self.queue.modify_queue(|q| {
+            let (start, count) = match q.iter().position(|track| track.uuid() == from.uuid()) {
+                Some(start) => (start, count),
+                None => (0, count.saturating_sub(1)),
+            };
+            let end = (start + count).min(q.len());
+            // Taken out of the queue first, their end does not move it on again.
+            for track in q.drain(start..end) {
+                let _ = track.stop();
+            }
+            if start == 0 {
+                if let Some(next) = q.front() {
+                    let _ = next.play();
+                }
+            }
+        });
+    }
+
+    fn stop(&self) {
+        self.queue.stop();
+    }
+}
+
+/// Everything the bot keeps about the music in one guild.
+pub struct GuildSession<V = Connection> {
+    pub guild_id: GuildId,
+    settings: Arc<Settings>,
+    blocklists: Arc<Blocklists>,
+    cooldowns: Arc<Cooldowns>,
+    resolver: Arc<Resolver>,
+    commands: Mutex<()>,
+    /// Taken after `state` when both are held, never before.
+    connection: RwLock<Option<V>>,
+    pub state: RwLock<SessionState>,
+}
+
+impl<V: Voice> GuildSession<V> {
+    fn new(
+        guild_id: GuildId,
+        settings: Arc<Settings>,
+        blocklists: Arc<Blocklists>,
+        cooldowns: Arc<Cooldowns>,
+        resolver: Arc<Resolver>,
+    ) -> Self {
+        Self {
+            guild_id,
+            settings,
+            blocklists,
+            cooldowns,
+            resolver,
+            commands: Mutex::new(()),
+            connection: RwLock::new(None),
+            state: RwLock::new(SessionState::default()),
+        }
+    }
+
+    /// Serialises commands touching this guild's queue; hold the guard for the
+    /// duration of the change, but not while resolving sources.
+    pub async fn lock(&self) -> MutexGuard<'_, ()> {
+        self.commands.lock().await
+    }
+
+    /// Fails with the reason when `requester` is on the guild's blocklist.
+    pub async fn check_member(&self, requester: &Requester) -> Result<(), String> {
+        match requester {
+            Requester::Member { id, roles, .. } => {
+                self.blocklists
+                    .check_member(self.guild_id, *id, roles)
+                    .await
+            }
+            Requester::Bot => Ok(()),
+        }
+    }
+
+    /// Whether `requester` may control playback, from the player buttons or
+    /// the dashboard. Blocked members may not, and everyone but managers takes
+    /// from the same `control` cooldown as the commands do. Fails with the
+    /// reason, or `None` when the member was told so already.
+    pub async fn control(&self, requester: &Requester) -> Result<(), Option<String>> {
+        self.check_member(requester).await.map_err(Some)?;
+        let id = match requester {
+            Requester::Member { manager: true, .. } | Requester::Bot => return Ok(()),
+            Requester::Member { id, .. } => *id,
+        };
+
+        let limits = self.settings.get(self.guild_id).await.limits;
+        self.cooldowns
+            .take_bucket(&limits, self.guild_id, id, "control")
+            .await
+            .map_err(|wait| {
+                wait.map(|wait| {
+                    format!(
+                        "The player is on cooldown, try again in {}",
+                        format_duration(wait.max(Duration::from_secs(1)))
+                    )
+                })
+            })
+    }
+
+    /// The queued tracks, the current one first, or `None` when not connected.
+    pub async fn tracks(&self) -> Option<Vec<TrackHandle>> {
+        self.connection.read().await.as_ref().map(Voice::tracks)
+    }
+
+    /// Adds a source to the end of the queue, remembering who asked for it.
+    /// Every way of queueing music comes through here, so the guild's
+    /// blocklist, duplicate policy and queue limits apply to all of them;
+    /// callers hold [`GuildSession::lock`] for the checks to stay true.
+    pub async fn enqueue(
+        &self,
+        source: Input,
+        requester: &Requester,
+    ) -> Result<TrackHandle, Refusal> {
+        let volume = self.state.read().await.volume;
+        let connection = self.connection.read().await;
+        let voice = connection.as_ref().ok_or(Refusal::NotConnected)?;
+        self.admit(&voice.tracks(), &source.metadata, requester)
+            .await?;
+
+        let stream = match &source.metadata.source_url {
+            Some(url) => self.resolver.stream(url).await,
+            None => None,
+        };
+        let track = voice.enqueue(source).await;
+        if let Some(volume) = volume {
+            let _ = track.set_volume(volume);
+        }
+        if let Some(id) = requester.id() {
+            history::set_requester(&track, id).await;
+        }
+        if let Some(stream) = stream {
+            track.typemap().write().await.insert::<radio::Live>(stream);
+        }
+        Ok(track)
+    }
+
+    /// Whether a track may join the queue for `requester`.
+    async fn admit(
+        &self,
+        tracks: &[TrackHandle],
+        metadata: &Metadata,
+        requester: &Requester,
+    ) -> Result<(), Refusal> {
+        self.check_member(requester)
+            .await
+            .map_err(Refusal::Requester)?;
+        self.blocklists
+            .check_track(self.guild_id, requester.id(), metadata)
+            .await
+            .map_err(Refusal::Blocked)?;
+
+        let settings = self.settings.get(self.guild_id).await;
+        if settings.duplicates == DuplicatePolicy::Reject {
+            let position = metadata
+                .source_url
+                .as_deref()
+                .and_then(|url| duplicates::position(tracks, url));
+            if let Some(position) = position {
+                return Err(Refusal::Duplicate(format!(
+                    "That song is already queued, {}",
+                    duplicates::place(position)
+                )));
+            }
+        }
+
+        if let Requester::Member {
+            id, manager: false, ..
+        } = requester
+        {
+            limits::allowance(&settings.limits, tracks, *id)
+                .await
+                .take(metadata.duration)
+                .map_err(Refusal::Limit)?;
+        }
+        Ok(())
+    }
+
+    /// Skips the current track, fading it out first when enabled; each skip
+    /// while it fades skips one more track. Returns how many tracks are left
+    /// afterwards, or `None` when the queue is cleared instead.
+    pub async fn skip(self: &Arc<Self>) -> Option<usize> {
+        match transitions::fade_out(self, FadeEnd::Skip(1)).await {
+            Some(FadeEnd::Skip(count)) => {
+                let queued = self.tracks().await.unwrap_or_default().len();
+                Some(queued.saturating_sub(count))
+            }
+            Some(FadeEnd::Stop) => None,
+            None => {
+                let connection = self.connection.read().await;
+                let voice = connection.as_ref()?;
+                if let Some(current) = voice.tracks().first() {
+                    voice.skip(current, 1);
+                }
+                Some(voice.tracks().len())
+            }
+        }
+    }
+
+    /// Clears the queue, fading the current track out first when enabled.
+    pub async fn stop(self: &Arc<Self>) {
+        if transitions::fade_out(self, FadeEnd::Stop).await.is_none() {
+            if let Some(voice) = self.connection.read().await.as_ref() {
+                voice.stop();
+            }
+        }
+    }
+
+    /// Skips or stops as asked while `fading` faded out.
+    pub async fn end_fade(&self, fading: &TrackHandle, end: FadeEnd) {
+        if let Some(voice) = self.connection.read().await.as_ref() {
+            match end {
+                FadeEnd::Skip(count) => voice.skip(fading, count),
+                FadeEnd::Stop => voice.stop(),
+            }
+        }
+    }
+}
+
+impl GuildSession {
+    pub async fn handler(&self) -> Option<Arc<Mutex<Call>>> {
+        self.connection
+            .read()
+            .await
+            .as_ref()
+            .map(|connection| connection.handler.clone())
+    }
+
+    /// The guild's track queue, which can be used without locking the call.
+    pub async fn queue(&self) -> Option<TrackQueue> {
+        self.connection
+            .read()
+            .await
+            .as_ref()
+            .map(|connection| connection.queue.clone())
+    }
+
+    /// Joins or moves to a voice channel, registering the session's events on
+    /// first connection.
+    pub async fn connect(
+        self: &Arc<Self>,
+        ctx: &Context,
+        channel_id: ChannelId,
+    ) -> Result<(), JoinError> {
+        let manager = songbird::get(ctx)
+            .await
+            .expect("Songbird Voice client placed in at initialisation.")
+            .clone();
+
+        let resolver = self.resolver.clone();
+        let presence = presence::presence(ctx).await;
+        let api = api::api(ctx).await;
+        let settings = settings::settings(ctx).await;
+        let loudness = loudness::loudness(ctx).await;
+
+        let (handler_lock, success) = manager.join(self.guild_id, channel_id).await;
+        success?;
+
+        let mut connection = self.connection.write().await;
+        if connection.is_none() {
+            let mut handler = handler_lock.lock().await;
+            player::register_events(ctx, self.clone(), &mut handler);
+            history::register_events(self.clone(), &mut handler);
+            autoplay::register_events(
+                ctx,
+                self.clone(),
+                resolver.clone(),
+                settings.clone(),
+                &mut handler,
+            );
+            metrics::register_events(&mut handler);
+            transitions::register_events(self.clone(), settings.clone(), &mut handler);
+            loudness::register_events(loudness, settings, self.guild_id, &mut handler);
+            if let Some(cache) = resolver.cache() {
+                cache::register_events(cache, &mut handler);
+            }
+            presence::register_events(presence, &mut handler);
+            api::register_events(api, self.guild_id, &mut handler);
+            stay::register_events(ctx, self.clone(), &mut handler);
+            radio::register_events(net::client(), &mut handler);
+
+            *connection = Some(Connection {
+                handler: handler_lock.clone(),
+                queue: handler.queue().clone(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Leaves the voice channel, dropping the queue along with the call.
+    pub async fn disconnect(&self, ctx: &Context) -> Result<(), JoinError> {
+        let manager = songbird::get(ctx)
+            .await
+            .expect("Songbird Voice client placed in at initialisation.")
+            .clone();
+
+        self.close(&manager).await
+    }
+
+    /// Like [`GuildSession::disconnect`], for when no `Context` is at hand.
+    pub async fn close(&self, manager: &Songbird) -> Result<(), JoinError> {
+        self.connection.write().await.take();
+        let mut state = self.state.write().await;
+        state.player = None;
+        state.fade = None;
+        state.overlap = None;
+        if let Some(timer) = state.sleep.take() {
+            timer.cancel();
+        }
+        drop(state);
+        manager.remove(self.guild_id).await
+    }
+}
+
+/// All guild sessions of the bot.
+pub struct Sessions {
+    settings: Arc<Settings>,
+    blocklists: Arc<Blocklists>,
+    cooldowns: Arc<Cooldowns>,
+    resolver: Arc<Resolver>,
+    sessions: Mutex<HashMap<GuildId, Arc<GuildSession>>>,
+}
+
+impl TypeMapKey for Sessions {
+    type Value = Arc<Sessions>;
+}
+
+impl Sessions {
+    pub fn new(
+        settings: Arc<Settings>,
+        blocklists: Arc<Blocklists>,
+        cooldowns: Arc<Cooldowns>,
+        resolver: Arc<Resolver>,
+    ) -> Self {
+        Self {
+            settings,
+            blocklists,
+            cooldowns,
+            resolver,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get(&self, guild_id: GuildId) -> Arc<GuildSession> {
+        self.sessions
+            .lock()
+            .await
+            .entry(guild_id)
+            .or_insert_with(|| {
+                Arc::new(GuildSession::new(
+                    guild_id,
+                    self.settings.clone(),
+                    self.blocklists.clone(),
+                    self.cooldowns.clone(),
+                    self.resolver.clone(),
+                ))
+            })
+            .clone()
+    }
+
+    pub async fn all(&self) -> Vec<Arc<GuildSession>> {
+        self.sessions.lock().await.values().cloned().collect()
+    }
+}
+
+pub async fn sessions(ctx: &Context) -> Arc<Sessions> {
+    let data = ctx.data.read().await;
+    data.get::<Sessions>()
+        .expect("Sessions placed in at initialisation.")
+        .clone()
+}
+
+/// Looks up the session of a guild, creating it on first use.
+pub async fn get(ctx: &Context, guild_id: GuildId) -> Arc<GuildSession> {
+    sessions(ctx).await.get(guild_id).await
+}
+
+/// Joins the voice channel of the message author, replying when that fails.
+pub async fn join_author(ctx: &Context, msg: &Message, session: &Arc<GuildSession>) -> bool {
+    let channel_id = msg.guild(&ctx.cache).and_then(|guild| {
+        guild
+            .voice_states
+            .get(&msg.author.id)
+            .and_then(|voice_state| voice_state.channel_id)
+    });
+
+    let connect_to = match channel_id {
+        Some(channel) => channel,
+        None => {
+            check_msg(msg.reply(ctx, "Not in a voice channel").await);
+
+            return false;
+        }
+    };
+
+    match session.connect(ctx, connect_to).await {
+        Ok(()) => {
+            session.state.write().await.channel = Some(msg.channel_id);
+            true
+        }
+        Err(why) => {
+            println!("Err joining channel: {:?}", why);
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Error joining the channel")
+                    .await,
+            );
+
+            false
+        }
+    }
+}
+
+/// Resolves and enqueues several URLs in order, then reports the queue length.
+pub async fn enqueue_urls(ctx: &Context, msg: &Message, session: &GuildSession, urls: Vec<String>) {
+    let (urls, mut duplicates) = duplicates::filter(ctx, msg, session, urls).await;
+    let sources = sources::resolver(ctx)
+        .await
+        .resolve_all(urls, session.guild_id)
+        .await;
+    let requester = Requester::author(ctx, msg).await;
+
+    let _guard = session.lock().await;
+    let mut refused = None;
+    let mut blocked = Vec::new();
+    for (_url, source) in sources {
+        match source {
+            Ok(source) => match session.enqueue(source, &requester).await {
+                Ok(_) => {}
+                Err(Refusal::Blocked(why)) => blocked.push(why),
+                Err(Refusal::Duplicate(_)) => duplicates += 1,
+                Err(why) => {
+                    refused = Some(why);
+                    break;
+                }
+            },
+            Err(why) => {
+                println!("Err starting source: {:?}", why);
+                check_msg(
+                    msg.channel_id
+                        .say(&ctx.http, format!("Err starting source: {:?}", why))
+                        .await,
+                );
+            }
+        }
+    }
+
+    match blocked.len() {
+        0 => {}
+        1 => check_msg(msg.reply(ctx, &blocked[0]).await),
+        count => check_msg(
+            msg.reply(ctx, format!("Skipped **{}** blocked songs", count))
+                .await,
+        ),
+    }
+    if duplicates > 0 {
+        check_msg(
+            msg.reply(
+                ctx,
+                format!("Skipped **{}** songs already in the queue", duplicates),
+            )
+            .await,
+        );
+    }
+    let queued = match (refused, session.queue().await) {
+        (Some(Refusal::NotConnected), _) | (_, None) => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Not in a voice channel to play in")
+                    .await,
+            );
+
+            return;
+        }
+        (Some(why), Some(queue)) => {
+            check_msg(msg.reply(ctx, why.to_string()).await);
+            queue.len()
+        }
+        (None, Some(queue)) => queue.len(),
+    };
+    check_msg(
+        msg.channel_id
+            .say(&ctx.http, format!("**{}** Songs in queue", queued))
+            .await,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex as SyncMutex,
+    };
+
+    use songbird::{
+        input::{Codec, Container, Metadata, Reader},
+        tracks::{self, Track},
+    };
+
+    use super::*;
+    use crate::{limits::Limits, loudness::Loudness, settings::GuildSettings};
+
+    /// Keeps a queue in memory, failing when two calls overlap.
+    #[derive(Default)]
+    struct FakeVoice {
+        queue: SyncMutex<VecDeque<TrackHandle>>,
+        /// Holds on to the tracks, so commands sent to their handles succeed.
+        tracks: SyncMutex<Vec<Track>>,
+        busy: AtomicBool,
+    }
+
+    impl FakeVoice {
+        fn titles(&self) -> Vec<String> {
+            self.queue.lock().unwrap().iter().map(title).collect()
+        }
+    }
+
+    #[async_trait]
+    impl Voice for FakeVoice {
+        async fn enqueue(&self, source: Input) -> TrackHandle {
+            assert!(
+                !self.busy.swap(true, Ordering::SeqCst),
+                "queue changed concurrently"
+            );
+            // Gives other tasks the chance to interleave, as awaiting the call does.
+            tokio::task::yield_now().await;
+
+            let (track, handle) = tracks::create_player(source);
+            self.tracks.lock().unwrap().push(track);
+            self.queue.lock().unwrap().push_back(handle.clone());
+            self.busy.store(false, Ordering::SeqCst);
+            handle
+        }
+
+        fn tracks(&self) -> Vec<TrackHandle> {
+            self.queue.lock().unwrap().iter().cloned().collect()
+        }
+
+        fn skip(&self, from: &TrackHandle, count: usize) {
+            let mut queue = self.queue.lock().unwrap();
+            let (start, count) = match queue.iter().position(|track| track.uuid() == from.uuid()) {
+                Some(start) => (start, count),
+                None => (0, count.saturating_sub(1)),
+            };
+            let end = (start + count).min(queue.len());
+            queue.drain(start..end);
+        }
+
+        fn stop(&self) {
+            self.queue.lock().unwrap().clear();
+        }
+    }
+
+    fn source(title: &str) -> Input {
+        Input::new(
+            true,
+            Reader::from_memory(Vec::new()),
+            Codec::FloatPcm,
+            Container::Raw,
+            Some(Metadata {
+                title: Some(title.to_string()),
+                source_url: Some(format!("https://example.com/{}", title)),
+                ..Default::default()
+            }),
+        )
+    }
+
+    fn member(id: u64) -> Requester {
+        Requester::Member {
+            id: UserId(id),
+            roles: Vec::new(),
+            manager: false,
+        }
+    }
+
+    fn title(track: &TrackHandle) -> String {
+        track.metadata().title.clone().unwrap_or_default()
+    }
+
+    async fn connected() -> Arc<GuildSession<FakeVoice>> {
+        with_settings(GuildSettings::default()).await
+    }
+
+    async fn with_settings(settings: GuildSettings) -> Arc<GuildSession<FakeVoice>> {
+        let settings = Arc::new(Settings::with(GuildId(1), settings));
+        let session = Arc::new(GuildSession::new(
+            GuildId(1),
+            settings.clone(),
+            Arc::new(Blocklists::default()),
+            Arc::new(Cooldowns::default()),
+            Arc::new(Resolver::new(
+                None,
+                settings,
+                Arc::new(Loudness::load().await),
+            )),
+        ));
+        *session.connection.write().await = Some(FakeVoice::default());
+        session
+    }
+
+    async fn titles(session: &GuildSession<FakeVoice>) -> Vec<String> {
+        session.connection.read().await.as_ref().unwrap().titles()
+    }
+
+    async fn play(session: &Arc<GuildSession<FakeVoice>>, titles: &[&str]) {
+        for name in titles {
+            session
+                .enqueue(source(name), &Requester::Bot)
+                .await
+                .unwrap();
+        }
+    }
+
+    /// The track fading out, which the fade event would finish.
+    async fn fading(session: &GuildSession<FakeVoice>) -> (TrackHandle, FadeEnd) {
+        session
+            .state
+            .read()
+            .await
+            .fade
+            .clone()
+            .expect("no pending fade")
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_plays_queue_in_lock_order() {
+        let session = connected().await;
+        let order = Arc::new(SyncMutex::new(Vec::new()));
+
+        let tasks: Vec<_> = (0..32)
+            .map(|n| {
+                let session = session.clone();
+                let order = order.clone();
+                tokio::spawn(async move {
+                    let _guard = session.lock().await;
+                    order.lock().unwrap().push(n.to_string());
+                    session
+                        .enqueue(source(&n.to_string()), &Requester::Bot)
+                        .await
+                        .unwrap();
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(titles(&session).await, *order.lock().unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_play_skip_stop_keep_queue_order() {
+        let session = connected().await;
+        play(&session, &["a", "b", "c", "d"]).await;
+
+        let tasks: Vec<_> = (0..3)
+            .map(|n| {
+                let session = session.clone();
+                tokio::spawn(async move {
+                    let _guard = session.lock().await;
+                    session
+                        .enqueue(source(&format!("new{}", n)), &Requester::Bot)
+                        .await
+                        .unwrap();
+                    session.skip().await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // The first skip fades out `a`, the others skip `b` and `c` with it.
+        let (track, end) = fading(&session).await;
+        assert_eq!(title(&track), "a");
+        assert_eq!(end, FadeEnd::Skip(3));
+        transitions::finish_fade(&session, &track).await;
+
+        let queued = titles(&session).await;
+        assert_eq!(queued[0], "d");
+        assert_eq!(queued.len(), 4);
+        assert!(queued[1..].iter().all(|name| name.starts_with("new")));
+        assert!(session.state.read().await.fade.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn stop_while_fading_clears_the_queue() {
+        let session = connected().await;
+        play(&session, &["a", "b", "c"]).await;
+
+        let skip = {
+            let session = session.clone();
+            tokio::spawn(async move {
+                let _guard = session.lock().await;
+                session.skip().await
+            })
+        };
+        let stop = {
+            let session = session.clone();
+            tokio::spawn(async move {
+                let _guard = session.lock().await;
+                session.stop().await
+            })
+        };
+        skip.await.unwrap();
+        stop.await.unwrap();
+
+        // Whichever came first, the stop takes over the pending fade.
+        let (track, end) = fading(&session).await;
+        assert_eq!(end, FadeEnd::Stop);
+        transitions::finish_fade(&session, &track).await;
+        assert!(titles(&session).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fade_finishes_once_after_the_track_ended() {
+        let session = connected().await;
+        play(&session, &["a", "b", "c", "d"]).await;
+
+        let _ = session.skip().await;
+        let _ = session.skip().await;
+        let (track, end) = fading(&session).await;
+        assert_eq!(end, FadeEnd::Skip(2));
+
+        // `a` ends on its own before the fade is over, and the queue moves on.
+        session
+            .connection
+            .read()
+            .await
+            .as_ref()
+            .unwrap()
+            .queue
+            .lock()
+            .unwrap()
+            .pop_front();
+        transitions::finish_fade(&session, &track).await;
+        transitions::finish_fade(&session, &track).await;
+
+        assert_eq!(titles(&session).await, ["c", "d"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_plays_stay_within_the_track_limit() {
+        let session = with_settings(GuildSettings {
+            limits: Limits {
+                max_tracks: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await;
+
+        let tasks: Vec<_> = (0..8)
+            .map(|n| {
+                let session = session.clone();
+                tokio::spawn(async move {
+                    let _guard = session.lock().await;
+                    session.enqueue(source(&n.to_string()), &member(7)).await
+                })
+            })
+            .collect();
+        let mut refused = 0;
+        for task in tasks {
+            if let Err(why) = task.await.unwrap() {
+                assert!(matches!(why, Refusal::Limit(_)), "{}", why);
+                refused += 1;
+            }
+        }
+
+        assert_eq!(refused, 6);
+        assert_eq!(titles(&session).await.len(), 2);
+        // Others and the bot are not held to the member's limit.
+        session.enqueue(source("other"), &member(8)).await.unwrap();
+        session
+            .enqueue(source("bot"), &Requester::Bot)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_duplicates_are_refused_on_every_path() {
+        let session = with_settings(GuildSettings {
+            duplicates: DuplicatePolicy::Reject,
+            ..Default::default()
+        })
+        .await;
+        play(&session, &["a", "b"]).await;
+
+        let refused = session.enqueue(source("b"), &member(7)).await;
+        assert!(matches!(refused, Err(Refusal::Duplicate(_))));
+        let refused = session.enqueue(source("a"), &Requester::Bot).await;
+        assert!(matches!(refused, Err(Refusal::Duplicate(_))));
+        assert_eq!(titles(&session).await, ["a", "b"]);
+    }
+}
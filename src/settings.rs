@@ -0,0 +1,90 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serenity::{
+    client::Context,
+    model::id::GuildId,
+    prelude::{RwLock, TypeMapKey},
+};
+
+use crate::{
+    duplicates::Policy as DuplicatePolicy, equalizer::Equalizer, limits::Limits, shards,
+    stay::AlwaysOn, storage,
+};
+
+/// Each process keeps the settings of the guilds on its own shards.
+fn settings_file() -> String {
+    format!("settings{}.json", shards::config().file_suffix())
+}
+
+/// Preferences a guild sets through commands, kept across restarts.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuildSettings {
+    /// Seconds consecutive tracks overlap for, `0` disabling crossfade.
+    pub crossfade: u64,
+    /// Seconds before the end of a track the next one is warmed up, `0`
+    /// disabling pre-buffering; unset for the `PREBUFFER_SECS` default.
+    pub prebuffer: Option<u64>,
+    /// The text channel autoplay announces its picks in, set while it is on.
+    pub autoplay: Option<u64>,
+    /// Whether tracks are brought to the same loudness.
+    pub normalize: bool,
+    pub eq: Equalizer,
+    /// Set while the guild keeps the bot in a voice channel around the clock.
+    pub always_on: Option<AlwaysOn>,
+    pub limits: Limits,
+    /// What happens when a song which is already queued is added again.
+    pub duplicates: DuplicatePolicy,
+}
+
+/// The settings of every guild, saved to the data directory on change.
+pub struct Settings {
+    guilds: RwLock<HashMap<u64, GuildSettings>>,
+}
+
+impl TypeMapKey for Settings {
+    type Value = Arc<Settings>;
+}
+
+impl Settings {
+    pub async fn load() -> Self {
+        Self {
+            guilds: RwLock::new(storage::load(&settings_file()).await),
+        }
+    }
+
+    /// Settings of a single guild which are never saved.
+    #[cfg(test)]
+    pub fn with(guild_id: GuildId, settings: GuildSettings) -> Self {
+        Self {
+            guilds: RwLock::new(HashMap::from([(guild_id.0, settings)])),
+        }
+    }
+
+    pub async fn get(&self, guild_id: GuildId) -> GuildSettings {
+        self.guilds
+            .read()
+            .await
+            .get(&guild_id.0)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Changes the settings of a guild and saves them.
+    pub async fn update<R>(&self, guild_id: GuildId, f: impl FnOnce(&mut GuildSettings) -> R) -> R {
+        let mut guilds = self.guilds.write().await;
+        let result = f(guilds.entry(guild_id.0).or_default());
+        if let Err(why) = storage::save(&settings_file(), &*guilds).await {
+            println!("Err saving settings: {:?}", why);
+        }
+        result
+    }
+}
+
+pub async fn settings(ctx: &Context) -> Arc<Settings> {
+    let data = ctx.data.read().await;
+    data.get::<Settings>()
+        .expect("Settings placed in at initialisation.")
+        .clone()
+}
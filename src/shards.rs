@@ -0,0 +1,213 @@
+use std::{env, fs, sync::Arc};
+
+use serenity::{
+    client::{
+        bridge::gateway::{event::ShardStageUpdateEvent, ShardId, ShardManager},
+        Client, Context,
+    },
+    framework::standard::{macros::command, CommandResult},
+    model::channel::Message,
+    prelude::{Mutex, TypeMapKey},
+    Result as SerenityResult,
+};
+
+use crate::storage;
+
+/// Data files kept per process, named with [`ShardConfig::file_suffix`].
+const DATA_FILES: [&str; 8] = [
+    "api_tokens",
+    "blocklists",
+    "loudness",
+    "playlists",
+    "podcasts",
+    "schedules",
+    "settings",
+    "snapshot",
+];
+
+/// Which shards this process runs, configured through `SHARDS`:
+/// `auto` (the default), a total like `4`, or a range like `0-1/4` for
+/// splitting the shards over several processes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShardConfig {
+    Auto,
+    Total(u64),
+    /// First and last shard, inclusive, out of the total.
+    Range(u64, u64, u64),
+}
+
+pub fn config() -> ShardConfig {
+    let value = match env::var("SHARDS") {
+        Ok(value) => value,
+        Err(_) => return ShardConfig::Auto,
+    };
+
+    match parse(&value) {
+        Some(config) => config,
+        None => {
+            println!("Err parsing SHARDS {}, falling back to auto", value);
+            ShardConfig::Auto
+        }
+    }
+}
+
+fn parse(value: &str) -> Option<ShardConfig> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("auto") {
+        return Some(ShardConfig::Auto);
+    }
+
+    match value.split_once('/') {
+        Some((range, total)) => {
+            let (first, last) = range.split_once('-')?;
+            let (first, last, total) = (
+                first.trim().parse().ok()?,
+                last.trim().parse().ok()?,
+                total.trim().parse().ok()?,
+            );
+            (first <= last && last < total).then_some(ShardConfig::Range(first, last, total))
+        }
+        None => value
+            .parse()
+            .ok()
+            .filter(|total| *total > 0)
+            .map(ShardConfig::Total),
+    }
+}
+
+impl ShardConfig {
+    /// Suffix for files only this process may write, so processes sharing a
+    /// data directory do not overwrite each other. Changing `SHARDS` changes
+    /// the suffix, and the data of the guilds moves with it only when merged
+    /// into the new files by hand, see [`check_data_files`].
+    pub fn file_suffix(&self) -> String {
+        match self {
+            ShardConfig::Range(first, last, total) => format!("-{}-{}-{}", first, last, total),
+            _ => String::new(),
+        }
+    }
+
+    pub async fn start(&self, client: &mut Client) -> SerenityResult<()> {
+        match *self {
+            ShardConfig::Auto => client.start_autosharded().await,
+            ShardConfig::Total(total) => client.start_shards(total).await,
+            ShardConfig::Range(first, last, total) => {
+                client.start_shard_range([first, last], total).await
+            }
+        }
+    }
+}
+
+/// Warns about data files this process starts without while files written
+/// under other `SHARDS` settings exist, which is what changing the split
+/// leaves behind.
+pub fn check_data_files() {
+    let names: Vec<String> = match fs::read_dir(storage::data_dir()) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .collect(),
+        Err(_) => return,
+    };
+    let suffix = config().file_suffix();
+
+    for base in DATA_FILES {
+        let own = format!("{}{}.json", base, suffix);
+        if names.contains(&own) {
+            continue;
+        }
+        let others: Vec<&str> = names
+            .iter()
+            .filter(|name| is_data_file(name, base))
+            .map(String::as_str)
+            .collect();
+        if !others.is_empty() {
+            println!(
+                "Found {} written under other SHARDS settings but no {}; merge the guilds of this process into it by hand",
+                others.join(", "),
+                own
+            );
+        }
+    }
+}
+
+/// Whether a file name is `base.json` or `base-first-last-total.json`.
+fn is_data_file(name: &str, base: &str) -> bool {
+    let suffix = match name
+        .strip_suffix(".json")
+        .and_then(|stem| stem.strip_prefix(base))
+    {
+        Some(suffix) => suffix,
+        None => return false,
+    };
+    suffix.is_empty()
+        || suffix
+            .strip_prefix('-')
+            .map(|range| range.split('-').collect::<Vec<_>>())
+            .is_some_and(|parts| {
+                parts.len() == 3
+                    && parts
+                        .iter()
+                        .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+            })
+}
+
+pub struct ShardManagerContainer;
+
+impl TypeMapKey for ShardManagerContainer {
+    type Value = Arc<Mutex<ShardManager>>;
+}
+
+pub fn log_stage(event: &ShardStageUpdateEvent) {
+    println!(
+        "Shard {} changed from {} to {}",
+        event.shard_id.0, event.old, event.new
+    );
+}
+
+#[command]
+#[aliases(shards)]
+async fn shardinfo(ctx: &Context, msg: &Message) -> CommandResult {
+    let shard_manager = {
+        let data = ctx.data.read().await;
+        data.get::<ShardManagerContainer>()
+            .expect("ShardManagerContainer placed in at initialisation.")
+            .clone()
+    };
+
+    let lines = {
+        let manager = shard_manager.lock().await;
+        let runners = manager.runners.lock().await;
+
+        let mut ids: Vec<&ShardId> = runners.keys().collect();
+        ids.sort_by_key(|id| id.0);
+
+        ids.into_iter()
+            .map(|id| {
+                let runner = &runners[id];
+                let latency = match runner.latency {
+                    Some(latency) => format!("{}ms", latency.as_millis()),
+                    None => String::from("unknown"),
+                };
+                format!(
+                    "{} Shard **{}**: {}, latency {}",
+                    if id.0 == ctx.shard_id { "▶" } else { "-" },
+                    id.0,
+                    runner.stage,
+                    latency
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    msg.channel_id
+        .send_message(&ctx.http, |m| {
+            m.embed(|e| {
+                e.title(format!("Shards ({} total)", ctx.cache.shard_count()));
+                e.description(lines.join("\n"));
+                e
+            })
+        })
+        .await?;
+
+    Ok(())
+}
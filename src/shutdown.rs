@@ -0,0 +1,230 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serenity::{
+    client::Context,
+    http::Http,
+    model::{
+        gateway::Ready,
+        id::{ChannelId, GuildId, UserId},
+    },
+    prelude::{Mutex, TypeMapKey},
+};
+use songbird::Songbird;
+use tokio::signal;
+
+use crate::{
+    check_msg, history,
+    session::{self, Requester, Sessions},
+    shards, sources, storage,
+};
+
+/// Each process only snapshots the guilds of its own shards.
+fn snapshot_file() -> String {
+    format!("snapshot{}.json", shards::config().file_suffix())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotTrack {
+    pub url: String,
+    #[serde(default)]
+    pub requester: Option<u64>,
+}
+
+/// The queue of one guild at the time the bot shut down.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GuildSnapshot {
+    pub guild_id: u64,
+    pub voice_channel: u64,
+    #[serde(default)]
+    pub text_channel: Option<u64>,
+    pub tracks: Vec<SnapshotTrack>,
+    /// Playback position of the first track, in seconds.
+    #[serde(default)]
+    pub position: u64,
+}
+
+/// Snapshots left by the previous run, restored as their shards become ready.
+pub struct Snapshots;
+
+impl TypeMapKey for Snapshots {
+    type Value = Arc<Mutex<Vec<GuildSnapshot>>>;
+}
+
+/// Takes the snapshots of the previous run, so a crash does not restore them twice.
+pub async fn load() -> Arc<Mutex<Vec<GuildSnapshot>>> {
+    let snapshots: Vec<GuildSnapshot> = storage::load(&snapshot_file()).await;
+    if !snapshots.is_empty() {
+        if let Err(why) = storage::save(&snapshot_file(), &Vec::<GuildSnapshot>::new()).await {
+            println!("Err clearing snapshot: {:?}", why);
+        }
+    }
+
+    Arc::new(Mutex::new(snapshots))
+}
+
+/// Resolves once the process receives SIGINT or SIGTERM.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Err registering SIGTERM handler");
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+    }
+}
+
+/// Announces the shutdown, snapshots every queue to disk and leaves all voice channels.
+pub async fn shutdown(http: &Http, manager: &Songbird, sessions: &Sessions) {
+    let mut snapshots = Vec::new();
+
+    for session in sessions.all().await {
+        let _guard = session.lock().await;
+        let handler_lock = match session.handler().await {
+            Some(handler) => handler,
+            None => continue,
+        };
+        let voice_channel = handler_lock.lock().await.current_channel();
+        let text_channel = session.state.read().await.channel;
+
+        let mut tracks = Vec::new();
+        let mut position = Duration::ZERO;
+        if let Some(queue) = session.queue().await {
+            for (index, track) in queue.current_queue().iter().enumerate() {
+                let url = match &track.metadata().source_url {
+                    Some(url) => url.clone(),
+                    None => continue,
+                };
+                if index == 0 {
+                    if let Ok(info) = track.get_info().await {
+                        position = info.position;
+                    }
+                }
+                tracks.push(SnapshotTrack {
+                    url,
+                    requester: history::requester(track).await.map(|user| user.0),
+                });
+            }
+        }
+
+        let voice_channel = voice_channel.filter(|_| !tracks.is_empty());
+        if let Some(voice_channel) = voice_channel {
+            if let Some(channel) = text_channel {
+                check_msg(
+                    channel
+                        .say(
+                            http,
+                            "Shutting down, the queue will be back after the restart",
+                        )
+                        .await,
+                );
+            }
+
+            snapshots.push(GuildSnapshot {
+                guild_id: session.guild_id.0,
+                voice_channel: voice_channel.0,
+                text_channel: text_channel.map(|channel| channel.0),
+                tracks,
+                position: position.as_secs(),
+            });
+        }
+
+        if let Err(why) = session.close(manager).await {
+            println!("Err leaving {}: {:?}", session.guild_id, why);
+        }
+    }
+
+    println!("Saving {} queue snapshots", snapshots.len());
+    if let Err(why) = storage::save(&snapshot_file(), &snapshots).await {
+        println!("Err saving snapshot: {:?}", why);
+    }
+}
+
+/// Rejoins and refills the queues of the guilds which just became ready.
+pub async fn restore(ctx: &Context, ready: &Ready) {
+    let pending = {
+        let data = ctx.data.read().await;
+        data.get::<Snapshots>()
+            .expect("Snapshots placed in at initialisation.")
+            .clone()
+    };
+
+    let snapshots: Vec<GuildSnapshot> = {
+        let mut pending = pending.lock().await;
+        let (ready_snapshots, rest) = pending.drain(..).partition(|snapshot| {
+            ready
+                .guilds
+                .iter()
+                .any(|guild| guild.id.0 == snapshot.guild_id)
+        });
+        *pending = rest;
+        ready_snapshots
+    };
+
+    for snapshot in snapshots {
+        let session = session::get(ctx, GuildId(snapshot.guild_id)).await;
+        if let Err(why) = session
+            .connect(ctx, ChannelId(snapshot.voice_channel))
+            .await
+        {
+            println!("Err restoring {}: {:?}", snapshot.guild_id, why);
+            continue;
+        }
+        session.state.write().await.channel = snapshot.text_channel.map(ChannelId);
+
+        let urls = snapshot
+            .tracks
+            .iter()
+            .map(|track| track.url.clone())
+            .collect();
+        let sources = sources::resolver(ctx)
+            .await
+            .resolve_all(urls, session.guild_id)
+            .await;
+
+        let _guard = session.lock().await;
+        let mut restored = 0;
+        for (index, ((_url, source), track)) in
+            sources.into_iter().zip(&snapshot.tracks).enumerate()
+        {
+            match source {
+                Ok(source) => {
+                    let requester = match track.requester {
+                        Some(user_id) => {
+                            Requester::member(ctx, session.guild_id, UserId(user_id)).await
+                        }
+                        None => Requester::Bot,
+                    };
+                    match session.enqueue(source, &requester).await {
+                        Ok(handle) => {
+                            if index == 0 {
+                                let _ = handle.seek_time(Duration::from_secs(snapshot.position));
+                            }
+                            restored += 1;
+                        }
+                        Err(why) => println!("Err restoring source: {}", why),
+                    }
+                }
+                Err(why) => println!("Err restoring source: {:?}", why),
+            }
+        }
+
+        if let Some(channel) = snapshot.text_channel {
+            check_msg(
+                ChannelId(channel)
+                    .say(
+                        &ctx.http,
+                        format!("Restored **{}** Songs after restart", restored),
+                    )
+                    .await,
+            );
+        }
+    }
+}
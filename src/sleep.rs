@@ -0,0 +1,207 @@
+use std::{sync::Arc, time::Duration};
+
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{channel::Message, id::ChannelId},
+};
+use tokio::{task::JoinHandle, time::Instant};
+
+use crate::{
+    check_msg,
+    session::{self, GuildSession},
+};
+
+/// The longest a sleep timer can be set for.
+const MAX_SLEEP: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How often the end of the current track is checked for.
+const TRACK_POLL: Duration = Duration::from_millis(250);
+
+/// When a sleeping session leaves the voice channel.
+#[derive(Clone, Copy)]
+enum Wake {
+    At(Instant),
+    EndOfTrack,
+}
+
+/// A pending stop and leave, kept in the session state.
+pub struct SleepTimer {
+    wake: Wake,
+    task: JoinHandle<()>,
+}
+
+impl SleepTimer {
+    pub fn cancel(self) {
+        self.task.abort();
+    }
+
+    fn describe(&self) -> String {
+        match self.wake {
+            Wake::At(at) => format!(
+                "Leaving in {}",
+                format_duration(at.saturating_duration_since(Instant::now()))
+            ),
+            Wake::EndOfTrack => String::from("Leaving after the current track"),
+        }
+    }
+}
+
+/// Parses durations like `30m`, `1h30m` or `90s`; a bare number means minutes.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    if let Ok(minutes) = text.parse::<u32>() {
+        return Some(Duration::from_secs(u64::from(minutes) * 60));
+    }
+
+    let mut total = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let value = u64::from(number.parse::<u32>().ok()?);
+        number.clear();
+        total += match c {
+            'h' => value * 60 * 60,
+            'm' => value * 60,
+            's' => value,
+            _ => return None,
+        };
+    }
+
+    if number.is_empty() && total > 0 {
+        Some(Duration::from_secs(total))
+    } else {
+        None
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, _) => format!("{}h {}m", h, m),
+    }
+}
+
+/// Waits until the timer is due, then leaves the voice channel.
+async fn run(ctx: Context, session: Arc<GuildSession>, channel_id: ChannelId, wake: Wake) {
+    match wake {
+        Wake::At(at) => tokio::time::sleep_until(at).await,
+        Wake::EndOfTrack => {
+            let playing = session
+                .queue()
+                .await
+                .and_then(|queue| queue.current())
+                .map(|track| track.uuid());
+            let mut poll = tokio::time::interval(TRACK_POLL);
+            loop {
+                poll.tick().await;
+                let current = session
+                    .queue()
+                    .await
+                    .and_then(|queue| queue.current())
+                    .map(|track| track.uuid());
+                if current.is_none() || current != playing {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _guard = session.lock().await;
+    // Dropping rather than cancelling the timer, which is this very task.
+    session.state.write().await.sleep = None;
+    if session.handler().await.is_none() {
+        return;
+    }
+    if let Err(why) = session.disconnect(&ctx).await {
+        println!("Err leaving after sleep timer: {:?}", why);
+        return;
+    }
+    check_msg(
+        channel_id
+            .say(&ctx.http, "Sleep timer is up, good night")
+            .await,
+    );
+}
+
+#[command]
+#[aliases(sleeptimer)]
+#[only_in(guilds)]
+async fn sleep(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let session = session::get(ctx, guild_id).await;
+
+    let wake = match args.rest().trim() {
+        "" => {
+            let reply = match &session.state.read().await.sleep {
+                Some(timer) => timer.describe(),
+                None => String::from("No sleep timer set"),
+            };
+            check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+            return Ok(());
+        }
+        "cancel" | "off" => {
+            let reply = match session.state.write().await.sleep.take() {
+                Some(timer) => {
+                    timer.cancel();
+                    "Sleep timer cancelled"
+                }
+                None => "No sleep timer set",
+            };
+            check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+            return Ok(());
+        }
+        "end" => Wake::EndOfTrack,
+        duration => match parse_duration(duration) {
+            Some(duration) if duration <= MAX_SLEEP => Wake::At(Instant::now() + duration),
+            _ => {
+                check_msg(
+                    msg.channel_id
+                        .say(
+                            &ctx.http,
+                            "Usage: sleep <duration like 30m or 1h30m>, sleep end, sleep cancel",
+                        )
+                        .await,
+                );
+
+                return Ok(());
+            }
+        },
+    };
+
+    let queue = match session.queue().await {
+        Some(queue) => queue,
+        None => {
+            check_msg(msg.reply(ctx, "Not in a voice channel").await);
+
+            return Ok(());
+        }
+    };
+    if matches!(wake, Wake::EndOfTrack) && queue.current().is_none() {
+        check_msg(msg.channel_id.say(&ctx.http, "Nothing is playing").await);
+
+        return Ok(());
+    }
+
+    let reply = {
+        // The timer clears itself from the state when it fires, so it is only
+        // started once the state is locked for storing it.
+        let mut state = session.state.write().await;
+        let task = tokio::spawn(run(ctx.clone(), session.clone(), msg.channel_id, wake));
+        let timer = SleepTimer { wake, task };
+        let reply = timer.describe();
+        if let Some(previous) = state.sleep.replace(timer) {
+            previous.cancel();
+        }
+        reply
+    };
+    check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+    Ok(())
+}
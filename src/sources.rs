@@ -0,0 +1,371 @@
+use std::{
+    collections::HashMap,
+    io,
+    process::{Command, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serenity::{
+    async_trait,
+    client::Context,
+    model::id::GuildId,
+    prelude::{RwLock, TypeMapKey},
+};
+use songbird::input::{
+    children_to_reader,
+    error::{Error as InputError, Result as InputResult},
+    restartable::{Restart, Restartable},
+    Codec, Container, Input, Metadata,
+};
+use tokio::sync::Semaphore;
+
+use crate::{
+    cache::{Lease, TrackCache},
+    loudness::{self, Loudness},
+    metrics::METRICS,
+    net,
+    radio::{self, Stream},
+    settings::Settings,
+    ytdl,
+};
+
+/// How many yt-dlp resolutions may run at once across all guilds.
+const MAX_CONCURRENT_RESOLUTIONS: usize = 4;
+
+/// How many resolved live streams are remembered for the tracks playing them.
+const MAX_STREAMS: usize = 256;
+
+/// How long a stream URL extracted by yt-dlp is reused for, well within the
+/// hours YouTube keeps them valid.
+const STREAM_URL_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Turns URLs into playable inputs, bounding how many yt-dlp processes run at once.
+pub struct Resolver {
+    permits: Arc<Semaphore>,
+    cache: Option<Arc<TrackCache>>,
+    settings: Arc<Settings>,
+    loudness: Arc<Loudness>,
+    /// Live streams which were resolved, by the URL they were queued with.
+    streams: RwLock<HashMap<String, Stream>>,
+}
+
+impl TypeMapKey for Resolver {
+    type Value = Arc<Resolver>;
+}
+
+impl Resolver {
+    pub fn new(
+        cache: Option<Arc<TrackCache>>,
+        settings: Arc<Settings>,
+        loudness: Arc<Loudness>,
+    ) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT_RESOLUTIONS)),
+            cache,
+            settings,
+            loudness,
+            streams: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn cache(&self) -> Option<Arc<TrackCache>> {
+        self.cache.clone()
+    }
+
+    /// Checks whether a URL is a live stream, see [`radio::probe`].
+    pub async fn probe(&self, url: &str) -> Option<Stream> {
+        radio::probe(&net::client(), url).await
+    }
+
+    /// The live stream a URL was resolved to, if it was one.
+    pub async fn stream(&self, url: &str) -> Option<Stream> {
+        self.streams.read().await.get(url).cloned()
+    }
+
+    async fn cached(&self, url: &str, guild_id: GuildId) -> Option<Result<Input, InputError>> {
+        let (lease, metadata) = self.cache.as_ref()?.get(url).await?;
+        let pipeline = Pipeline {
+            stream_url: None,
+            origin: Origin::Cached(lease),
+            metadata: Some(metadata),
+            settings: self.settings.clone(),
+            loudness: self.loudness.clone(),
+            guild_id,
+        };
+        Some(Restartable::new(pipeline, true).await.map(Input::from))
+    }
+
+    /// Resolves a URL for a guild, whose equalizer applies to the track.
+    pub async fn resolve(&self, url: String, guild_id: GuildId) -> Result<Input, InputError> {
+        let stream = self.probe(&url).await;
+        self.resolve_probed(url, stream, guild_id).await
+    }
+
+    /// Like [`Resolver::resolve`], for a URL which was probed already.
+    pub async fn resolve_probed(
+        &self,
+        url: String,
+        stream: Option<Stream>,
+        guild_id: GuildId,
+    ) -> Result<Input, InputError> {
+        if let Some(source) = self.cached(&url, guild_id).await {
+            return source;
+        }
+
+        if let Some(stream) = stream {
+            {
+                let mut streams = self.streams.write().await;
+                if streams.len() >= MAX_STREAMS && !streams.contains_key(&url) {
+                    streams.clear();
+                }
+                streams.insert(url.clone(), stream.clone());
+            }
+            let metadata = Metadata {
+                title: Some(stream.name.unwrap_or_else(|| url.clone())),
+                source_url: Some(url),
+                ..Default::default()
+            };
+            let pipeline = Pipeline {
+                stream_url: None,
+                origin: Origin::Stream(stream.url),
+                metadata: Some(metadata),
+                settings: self.settings.clone(),
+                loudness: self.loudness.clone(),
+                guild_id,
+            };
+            return Restartable::new(pipeline, true).await.map(Input::from);
+        }
+
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("Resolver semaphore is never closed.");
+
+        // Here, we use lazy restartable sources to make sure that we don't pay
+        // for decoding, playback on tracks which aren't actually live yet.
+        let started = Instant::now();
+        let pipeline = Pipeline {
+            stream_url: None,
+            origin: Origin::Ytdl(url),
+            metadata: None,
+            settings: self.settings.clone(),
+            loudness: self.loudness.clone(),
+            guild_id,
+        };
+        let source = Restartable::new(pipeline, true).await.map(Input::from);
+        METRICS.resolution(started.elapsed(), source.is_ok());
+        source
+    }
+
+    /// Prepares an audio file served over HTTP whose metadata is already
+    /// known, like a podcast episode from its feed.
+    pub async fn remote(
+        &self,
+        url: String,
+        metadata: Metadata,
+        guild_id: GuildId,
+    ) -> Result<Input, InputError> {
+        let pipeline = Pipeline {
+            stream_url: None,
+            origin: Origin::Remote(url),
+            metadata: Some(metadata),
+            settings: self.settings.clone(),
+            loudness: self.loudness.clone(),
+            guild_id,
+        };
+        Restartable::new(pipeline, true).await.map(Input::from)
+    }
+
+    /// Resolves several URLs concurrently, with a result for each URL in the
+    /// order they were given.
+    pub async fn resolve_all(
+        self: &Arc<Self>,
+        urls: Vec<String>,
+        guild_id: GuildId,
+    ) -> Vec<(String, Result<Input, InputError>)> {
+        let tasks: Vec<_> = urls
+            .iter()
+            .map(|url| {
+                let resolver = self.clone();
+                let url = url.clone();
+                tokio::spawn(async move { resolver.resolve(url, guild_id).await })
+            })
+            .collect();
+
+        let mut sources = Vec::with_capacity(tasks.len());
+        for (url, task) in urls.into_iter().zip(tasks) {
+            let source = match task.await {
+                Ok(source) => source,
+                Err(why) => {
+                    println!("Err joining resolution: {:?}", why);
+                    Err(io::Error::from(why).into())
+                }
+            };
+            sources.push((url, source));
+        }
+        sources
+    }
+}
+
+pub async fn resolver(ctx: &Context) -> Arc<Resolver> {
+    let data = ctx.data.read().await;
+    data.get::<Resolver>()
+        .expect("Resolver placed in at initialisation.")
+        .clone()
+}
+
+/// Where a pipeline reads its audio from.
+enum Origin {
+    /// A page yt-dlp can extract, re-extracted on every restart as stream URLs expire.
+    Ytdl(String),
+    /// A file in the track cache, kept there while the track lasts.
+    Cached(Lease),
+    /// An audio file served over HTTP, which ffmpeg reads directly.
+    Remote(String),
+    /// A live HTTP or HLS stream, like a radio station, which has no position to seek to.
+    Stream(String),
+}
+
+/// Decodes a track through ffmpeg with the guild's equalizer, which is read
+/// again whenever the track restarts or seeks. While loudness normalisation is
+/// on, the same ffmpeg measures tracks played from their start.
+///
+/// Restarts reuse the stream URL yt-dlp extracted while it is fresh, so seeking,
+/// which the equalizer relies on to apply a new filter, only restarts ffmpeg.
+struct Pipeline {
+    origin: Origin,
+    /// The stream URL extracted for a [`Origin::Ytdl`] track, and when.
+    stream_url: Option<(String, Instant)>,
+    metadata: Option<Metadata>,
+    settings: Arc<Settings>,
+    loudness: Arc<Loudness>,
+    guild_id: GuildId,
+}
+
+/// Runs yt-dlp, failing with its output when it does not succeed.
+async fn ytdl(args: &[&str]) -> InputResult<Vec<u8>> {
+    let output = tokio::process::Command::new("yt-dlp")
+        .args(["--no-playlist", "--ignore-config", "--no-warnings"])
+        .args(args)
+        .output()
+        .await?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(InputError::YouTubeDlRun(output))
+    }
+}
+
+#[async_trait]
+impl Restart for Pipeline {
+    async fn call_restart(&mut self, time: Option<Duration>) -> InputResult<Input> {
+        let (path, streamed) = match &self.origin {
+            Origin::Ytdl(url) => match &self.stream_url {
+                Some((stream_url, extracted)) if extracted.elapsed() < STREAM_URL_LIFETIME => {
+                    (stream_url.clone(), true)
+                }
+                _ => {
+                    let stdout = ytdl(&["-f", "bestaudio/best", "-g", url]).await?;
+                    let stream_url = String::from_utf8_lossy(&stdout)
+                        .lines()
+                        .next()
+                        .map(String::from)
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no stream URL"))?;
+                    self.stream_url = Some((stream_url.clone(), Instant::now()));
+                    (stream_url, true)
+                }
+            },
+            Origin::Cached(lease) => (lease.path().to_string(), false),
+            Origin::Remote(url) | Origin::Stream(url) => (url.clone(), true),
+        };
+        let settings = self.settings.get(self.guild_id).await;
+        let video_id = self
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.source_url.as_deref())
+            .and_then(ytdl::video_id);
+        let measure = match video_id {
+            Some(video_id)
+                if settings.normalize
+                    && time.is_none()
+                    && !matches!(self.origin, Origin::Stream(_))
+                    && !self.loudness.measured(&video_id).await =>
+            {
+                Some(video_id)
+            }
+            _ => None,
+        };
+        let filters: Vec<String> = measure
+            .as_ref()
+            .map(|_| String::from(loudness::FILTER))
+            .into_iter()
+            .chain(settings.eq.filter())
+            .collect();
+
+        let mut command = Command::new("ffmpeg");
+        if streamed {
+            command.args(["-reconnect", "1", "-reconnect_streamed", "1"]);
+            command.args(["-reconnect_delay_max", "5"]);
+        }
+        if let (Some(time), false) = (time, matches!(self.origin, Origin::Stream(_))) {
+            command.args(["-ss", &format!("{:.3}", time.as_secs_f64())]);
+        }
+        command.arg("-i").arg(&path).arg("-vn");
+        if !filters.is_empty() {
+            command.args(["-af", &filters.join(",")]);
+        }
+        let mut ffmpeg = command
+            .args([
+                "-f",
+                "s16le",
+                "-ac",
+                "2",
+                "-ar",
+                "48000",
+                "-acodec",
+                "pcm_f32le",
+                "-",
+            ])
+            .stdin(Stdio::null())
+            .stderr(if measure.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .spawn()?;
+        if let (Some(video_id), Some(stderr)) = (measure, ffmpeg.stderr.take()) {
+            self.loudness.listen(video_id, stderr);
+        }
+
+        Ok(Input::new(
+            true,
+            children_to_reader::<f32>(vec![ffmpeg]),
+            Codec::FloatPcm,
+            Container::Raw,
+            self.metadata.clone(),
+        ))
+    }
+
+    async fn lazy_init(&mut self) -> InputResult<(Option<Metadata>, Codec, Container)> {
+        if let Origin::Ytdl(url) = &self.origin {
+            let stdout = ytdl(&["-f", "bestaudio/best", "-j", url]).await?;
+            let value: serde_json::Value =
+                serde_json::from_slice(&stdout).map_err(|error| InputError::Json {
+                    error,
+                    parsed_text: String::from_utf8_lossy(&stdout).into_owned(),
+                })?;
+            // The selected format's URL comes with the metadata, sparing the
+            // first restart another run of yt-dlp.
+            self.stream_url = value
+                .get("url")
+                .and_then(|url| url.as_str())
+                .map(|url| (url.to_owned(), Instant::now()));
+            self.metadata = Some(Metadata::from_ytdl_output(value));
+        }
+
+        Ok((self.metadata.clone(), Codec::FloatPcm, Container::Raw))
+    }
+}
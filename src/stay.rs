@@ -0,0 +1,334 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serenity::{
+    async_trait,
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{
+        channel::Message,
+        gateway::Ready,
+        id::{ChannelId, GuildId, UserId},
+    },
+};
+use songbird::{
+    tracks::PlayMode, Call, CoreEvent, Event, EventContext, EventHandler as VoiceEventHandler,
+    TrackEvent,
+};
+
+use crate::{
+    check_msg, permissions, playlists,
+    session::{self, GuildSession, Requester},
+    settings, sources,
+};
+
+/// How long to wait before each attempt to rejoin after a disconnect.
+const REJOIN_DELAYS: [Duration; 5] = [
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(15),
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+];
+
+/// Where a guild in 24/7 mode keeps the bot, and what it plays when the
+/// queue runs out.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AlwaysOn {
+    pub voice_channel: u64,
+    pub text_channel: u64,
+    /// Who turned 24/7 mode on, whose saved playlists the fallback may use.
+    pub owner: u64,
+    /// The saved playlist played when the queue is empty, autoplay otherwise.
+    /// Only one of them refills the queue, so autoplay yields to the playlist.
+    pub playlist: Option<String>,
+}
+
+async fn always_on(ctx: &Context, guild_id: GuildId) -> Option<AlwaysOn> {
+    settings::settings(ctx).await.get(guild_id).await.always_on
+}
+
+/// Joins the 24/7 channel and fills the queue if there is nothing to play.
+async fn stay(ctx: &Context, session: &Arc<GuildSession>, config: &AlwaysOn) -> bool {
+    if let Err(why) = session.connect(ctx, ChannelId(config.voice_channel)).await {
+        println!("Err joining 24/7 channel: {:?}", why);
+        return false;
+    }
+
+    session.state.write().await.channel = Some(ChannelId(config.text_channel));
+
+    let empty = session
+        .queue()
+        .await
+        .map(|queue| queue.is_empty())
+        .unwrap_or(false);
+    if empty {
+        fill(ctx, session, config).await;
+    }
+    true
+}
+
+/// Queues the fallback playlist in random order.
+async fn fill(ctx: &Context, session: &GuildSession, config: &AlwaysOn) {
+    let name = match &config.playlist {
+        Some(name) => name,
+        None => return,
+    };
+    let playlist = match playlists::find(ctx, UserId(config.owner), session.guild_id, name).await {
+        Some(playlist) => playlist,
+        None => {
+            println!("Err finding 24/7 playlist {} in {}", name, session.guild_id);
+            return;
+        }
+    };
+
+    let mut urls: Vec<String> = playlist.tracks.into_iter().map(|track| track.url).collect();
+    urls.shuffle(&mut rand::thread_rng());
+    urls.truncate(playlists::MAX_PLAYLIST_TRACKS);
+
+    let sources = sources::resolver(ctx)
+        .await
+        .resolve_all(urls, session.guild_id)
+        .await;
+    let _guard = session.lock().await;
+    for (url, source) in sources {
+        match source {
+            Ok(source) => {
+                if let Err(why) = session.enqueue(source, &Requester::Bot).await {
+                    println!("Err queueing 24/7 source {}: {}", url, why);
+                }
+            }
+            Err(why) => println!("Err starting 24/7 source {}: {:?}", url, why),
+        }
+    }
+}
+
+/// Joins the 24/7 channels of the guilds which just became ready.
+pub async fn restore(ctx: &Context, ready: &Ready) {
+    for guild in &ready.guilds {
+        if let Some(config) = always_on(ctx, guild.id).await {
+            let session = session::get(ctx, guild.id).await;
+            stay(ctx, &session, &config).await;
+        }
+    }
+}
+
+/// Registers the events which rejoin after disconnects and refill the queue.
+pub fn register_events(ctx: &Context, session: Arc<GuildSession>, handler: &mut Call) {
+    handler.add_global_event(
+        Event::Core(CoreEvent::DriverDisconnect),
+        Rejoiner {
+            ctx: ctx.clone(),
+            session: session.clone(),
+            rejoining: Arc::new(AtomicBool::new(false)),
+        },
+    );
+    handler.add_global_event(
+        Event::Track(TrackEvent::End),
+        Refiller {
+            ctx: ctx.clone(),
+            session,
+            filling: Arc::new(AtomicBool::new(false)),
+        },
+    );
+}
+
+struct Rejoiner {
+    ctx: Context,
+    session: Arc<GuildSession>,
+    rejoining: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl VoiceEventHandler for Rejoiner {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        always_on(&self.ctx, self.session.guild_id).await?;
+        if self.rejoining.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+
+        let ctx = self.ctx.clone();
+        let session = self.session.clone();
+        let rejoining = self.rejoining.clone();
+        tokio::spawn(async move {
+            for delay in REJOIN_DELAYS {
+                tokio::time::sleep(delay).await;
+                // Left on purpose, or 24/7 mode was turned off meanwhile.
+                if session.handler().await.is_none() {
+                    break;
+                }
+                let config = match always_on(&ctx, session.guild_id).await {
+                    Some(config) => config,
+                    None => break,
+                };
+                if stay(&ctx, &session, &config).await {
+                    break;
+                }
+            }
+            rejoining.store(false, Ordering::SeqCst);
+        });
+
+        None
+    }
+}
+
+struct Refiller {
+    ctx: Context,
+    session: Arc<GuildSession>,
+    /// Set while the playlist is being queued, so a burst of ended tracks fills once.
+    filling: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl VoiceEventHandler for Refiller {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let tracks = match ctx {
+            EventContext::Track(tracks) => tracks,
+            _ => return None,
+        };
+        // Like autoplay, `stop` leaves the queue empty on purpose.
+        if !tracks
+            .iter()
+            .any(|(state, _)| state.playing == PlayMode::End)
+        {
+            return None;
+        }
+
+        let config = always_on(&self.ctx, self.session.guild_id).await?;
+        // Without a playlist, autoplay refills the queue instead.
+        config.playlist.as_ref()?;
+        let queue = self.session.queue().await?;
+        let ended: Vec<_> = tracks.iter().map(|(_, track)| track.uuid()).collect();
+        if queue
+            .current_queue()
+            .iter()
+            .any(|track| !ended.contains(&track.uuid()))
+        {
+            return None;
+        }
+
+        if self.filling.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+
+        // Resolving the playlist runs yt-dlp for every track, which would hold
+        // up the driver's other events.
+        let ctx = self.ctx.clone();
+        let session = self.session.clone();
+        let filling = self.filling.clone();
+        tokio::spawn(async move {
+            fill(&ctx, &session, &config).await;
+            filling.store(false, Ordering::SeqCst);
+        });
+
+        None
+    }
+}
+
+#[command("247")]
+#[aliases("stay", "24/7")]
+#[only_in(guilds)]
+async fn twenty_four_seven(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let settings = settings::settings(ctx).await;
+
+    let mode = args.single::<String>().unwrap_or_default().to_lowercase();
+    match mode.as_str() {
+        "" => {
+            let reply = match settings.get(guild_id).await.always_on {
+                Some(config) => format!(
+                    "24/7 mode is on in <#{}>, playing {} when the queue is empty",
+                    config.voice_channel,
+                    config
+                        .playlist
+                        .map(|name| format!("playlist **{}**", name))
+                        .unwrap_or_else(|| String::from("autoplay"))
+                ),
+                None => String::from("24/7 mode is off"),
+            };
+            check_msg(msg.channel_id.say(&ctx.http, reply).await);
+        }
+        "off" => {
+            if !permissions::require_manager(ctx, msg).await {
+                return Ok(());
+            }
+            settings
+                .update(guild_id, |guild| guild.always_on = None)
+                .await;
+            check_msg(msg.channel_id.say(&ctx.http, "24/7 mode disabled").await);
+        }
+        "on" => {
+            if !permissions::require_manager(ctx, msg).await {
+                return Ok(());
+            }
+
+            let playlist = match args.rest().trim() {
+                "" => None,
+                name => {
+                    if playlists::find(ctx, msg.author.id, guild_id, name)
+                        .await
+                        .is_none()
+                    {
+                        check_msg(
+                            msg.channel_id
+                                .say(&ctx.http, format!("No playlist named **{}**", name))
+                                .await,
+                        );
+
+                        return Ok(());
+                    }
+                    Some(name.to_string())
+                }
+            };
+
+            let session = session::get(ctx, guild_id).await;
+            if !session::join_author(ctx, msg, &session).await {
+                return Ok(());
+            }
+            let voice_channel = match session.handler().await {
+                Some(handler) => handler.lock().await.current_channel(),
+                None => None,
+            };
+            let voice_channel = match voice_channel {
+                Some(channel) => channel.0,
+                None => return Ok(()),
+            };
+
+            let config = AlwaysOn {
+                voice_channel,
+                text_channel: msg.channel_id.0,
+                owner: msg.author.id.0,
+                playlist,
+            };
+            settings
+                .update(guild_id, |guild| guild.always_on = Some(config.clone()))
+                .await;
+            stay(ctx, &session, &config).await;
+
+            check_msg(
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        format!("24/7 mode enabled in <#{}>", voice_channel),
+                    )
+                    .await,
+            );
+        }
+        _ => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Usage: 247 [on [playlist]|off]")
+                    .await,
+            );
+        }
+    }
+
+    Ok(())
+}
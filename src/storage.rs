@@ -0,0 +1,72 @@
+use std::{
+    collections::BTreeMap,
+    env, io,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as SyncMutex,
+    },
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Mutex;
+
+/// A lock per file, so saves of the same file happen one after the other.
+static WRITERS: SyncMutex<BTreeMap<String, Arc<Mutex<()>>>> = SyncMutex::new(BTreeMap::new());
+
+/// Numbers temporary files, which are also named after the process, as shards
+/// running in other processes may share the data directory.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Directory the bot keeps its state in, configurable through `DATA_DIR`.
+pub fn data_dir() -> PathBuf {
+    env::var("DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("data"))
+}
+
+/// Loads a JSON file from the data directory, falling back to the default when
+/// it does not exist yet or cannot be parsed.
+pub async fn load<T: DeserializeOwned + Default>(name: &str) -> T {
+    let path = data_dir().join(name);
+    match tokio::fs::read(&path).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|why| {
+            println!("Err parsing {}: {:?}", path.display(), why);
+            T::default()
+        }),
+        Err(why) if why.kind() == io::ErrorKind::NotFound => T::default(),
+        Err(why) => {
+            println!("Err reading {}: {:?}", path.display(), why);
+            T::default()
+        }
+    }
+}
+
+/// Writes a JSON file to the data directory, replacing it atomically.
+pub async fn save<T: Serialize + ?Sized>(name: &str, value: &T) -> io::Result<()> {
+    let dir = data_dir();
+    tokio::fs::create_dir_all(&dir).await?;
+    let bytes = serde_json::to_vec_pretty(value)?;
+
+    let writer = WRITERS
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default()
+        .clone();
+    let _writing = writer.lock().await;
+
+    let tmp = dir.join(format!(
+        "{}.{}.{}.tmp",
+        name,
+        process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    tokio::fs::write(&tmp, bytes).await?;
+    if let Err(why) = tokio::fs::rename(&tmp, dir.join(name)).await {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(why);
+    }
+    Ok(())
+}
@@ -0,0 +1,434 @@
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serenity::{
+    async_trait,
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::channel::Message,
+    prelude::Mutex,
+};
+use songbird::{
+    tracks::{LoopState, TrackHandle, TrackQueue},
+    Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+
+use crate::{
+    check_msg, loudness, permissions,
+    session::{GuildSession, Voice},
+    settings::{self, Settings},
+};
+
+/// How often the current track is checked for an upcoming transition, which is
+/// also the step size of volume ramps.
+const TICK: Duration = Duration::from_millis(200);
+
+/// The longest crossfade a guild can set.
+const MAX_CROSSFADE: u64 = 12;
+
+/// The longest pre-buffer a guild can set.
+const MAX_PREBUFFER: u64 = 60;
+
+/// How long before the end of a track the next one is warmed up for guilds
+/// which did not choose, configured through `PREBUFFER_SECS`; `0` disables
+/// pre-buffering.
+fn default_prebuffer() -> u64 {
+    env::var("PREBUFFER_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(10)
+}
+
+/// How long skipped or stopped tracks fade out for, configured through
+/// `FADE_OUT_MS`; `0` cuts them off immediately.
+fn fade_out_length() -> Duration {
+    let millis = env::var("FADE_OUT_MS")
+        .ok()
+        .and_then(|millis| millis.parse().ok())
+        .unwrap_or(1000);
+    Duration::from_millis(millis)
+}
+
+/// Registers the event which prepares and crossfades into the next track
+/// before the current one ends.
+pub fn register_events(session: Arc<GuildSession>, settings: Arc<Settings>, handler: &mut Call) {
+    let queue = handler.queue().clone();
+    handler.add_global_event(
+        Event::Periodic(TICK, None),
+        Transitioner {
+            queue,
+            settings,
+            session,
+            state: Mutex::new(TransitionState::default()),
+        },
+    );
+}
+
+struct Transitioner {
+    queue: TrackQueue,
+    settings: Arc<Settings>,
+    session: Arc<GuildSession>,
+    state: Mutex<TransitionState>,
+}
+
+#[derive(Default)]
+struct TransitionState {
+    /// The last track made playable ahead of time.
+    warmed: Option<TrackHandle>,
+    crossfade: Option<Crossfade>,
+}
+
+/// Two tracks playing at once while the volume moves from one to the other.
+struct Crossfade {
+    outgoing: TrackHandle,
+    incoming: TrackHandle,
+    length: Duration,
+    /// The guild's volume, which both tracks play at before their own
+    /// loudness normalisation gain.
+    volume: f32,
+}
+
+impl Crossfade {
+    /// Moves the volumes along, returning `false` once the outgoing track is gone.
+    async fn step(&self) -> bool {
+        match remaining(&self.outgoing).await {
+            Some(left) if !left.is_zero() => {
+                let share = (left.as_secs_f32() / self.length.as_secs_f32()).min(1.0);
+                let outgoing = self.volume * loudness::gain(&self.outgoing).await;
+                let incoming = self.volume * loudness::gain(&self.incoming).await;
+                let _ = self.outgoing.set_volume(outgoing * share);
+                let _ = self.incoming.set_volume(incoming * (1.0 - share));
+                true
+            }
+            _ => {
+                self.finish().await;
+                false
+            }
+        }
+    }
+
+    /// Brings the incoming track to its full volume, leaving the outgoing one be.
+    async fn finish(&self) {
+        let incoming = self.volume * loudness::gain(&self.incoming).await;
+        let _ = self.incoming.set_volume(incoming);
+    }
+}
+
+/// How much of the track is left, unless it has no known length like a stream.
+async fn remaining(track: &TrackHandle) -> Option<Duration> {
+    let duration = track.metadata().duration?;
+    let info = track.get_info().await.ok()?;
+    Some(duration.saturating_sub(info.position))
+}
+
+#[async_trait]
+impl VoiceEventHandler for Transitioner {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        let mut state = self.state.lock().await;
+
+        if let Some(crossfade) = &state.crossfade {
+            // A skip or stop takes the overlap over to fade the outgoing track
+            // out itself, see `fade_out`.
+            let cancelled = self
+                .session
+                .state
+                .read()
+                .await
+                .overlap
+                .as_ref()
+                .map(|track| track.uuid())
+                != Some(crossfade.incoming.uuid());
+            if cancelled {
+                crossfade.finish().await;
+            } else if crossfade.step().await {
+                return None;
+            }
+            state.crossfade = None;
+            self.session.state.write().await.overlap = None;
+        }
+
+        let tracks = self.queue.current_queue();
+        let (current, next) = match (tracks.first(), tracks.get(1)) {
+            (Some(current), Some(next)) => (current, next),
+            _ => return None,
+        };
+        let left = remaining(current).await?;
+        let settings = self.settings.get(self.session.guild_id).await;
+        let crossfade = Duration::from_secs(settings.crossfade);
+        let prebuffer = Duration::from_secs(settings.prebuffer.unwrap_or_else(default_prebuffer));
+
+        let warm_up = prebuffer.max(crossfade);
+        let already_warmed = state.warmed.as_ref().map(|track| track.uuid()) == Some(next.uuid());
+        if !warm_up.is_zero() && left <= warm_up && !already_warmed {
+            // Starts yt-dlp and ffmpeg for the lazy source, so the queue can switch
+            // over without waiting for them.
+            if let Err(why) = next.make_playable() {
+                println!("Err pre-buffering track: {:?}", why);
+            }
+            state.warmed = Some(next.clone());
+        }
+
+        if crossfade.is_zero() || left > crossfade || left < TICK {
+            return None;
+        }
+        let info = match current.get_info().await {
+            // A looping track never reaches its end, so it cannot fade into the next.
+            Ok(info) if info.loops == LoopState::Finite(0) => info,
+            _ => return None,
+        };
+
+        // The queue keeps the outgoing track in front until it ends, then plays
+        // the incoming one, which by then is already playing at full volume.
+        let _ = next.set_volume(0.0);
+        if let Err(why) = next.play() {
+            println!("Err starting crossfade: {:?}", why);
+            return None;
+        }
+        self.session.state.write().await.overlap = Some(next.clone());
+        state.crossfade = Some(Crossfade {
+            outgoing: current.clone(),
+            incoming: next.clone(),
+            length: left,
+            volume: info.volume / loudness::gain(current).await,
+        });
+
+        None
+    }
+}
+
+/// What happens to the queue once a fade-out is over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FadeEnd {
+    /// Skips this many tracks, the fading one included.
+    Skip(usize),
+    Stop,
+}
+
+impl FadeEnd {
+    /// Joins a skip or stop asked for while a track is already fading: skips
+    /// add up, and a stop takes over from skips.
+    fn join(self, other: FadeEnd) -> FadeEnd {
+        match (self, other) {
+            (FadeEnd::Skip(skips), FadeEnd::Skip(more)) => FadeEnd::Skip(skips + more),
+            _ => FadeEnd::Stop,
+        }
+    }
+}
+
+/// Fades the current track out before skipping it or clearing the queue. A
+/// guild fades one track at a time, so a skip or stop while it fades joins the
+/// pending one. Returns what happens once the fade is over, or `None` when
+/// fading is disabled or nothing is playing.
+pub async fn fade_out<V: Voice>(session: &Arc<GuildSession<V>>, end: FadeEnd) -> Option<FadeEnd> {
+    let mut state = session.state.write().await;
+    if let Some((_, pending)) = &mut state.fade {
+        *pending = pending.join(end);
+        return Some(*pending);
+    }
+
+    let length = fade_out_length();
+    let current = match session.tracks().await.unwrap_or_default().first() {
+        Some(current) if !length.is_zero() => current.clone(),
+        _ => return None,
+    };
+    // A crossfade would ramp the same track's volume against this fade, so it
+    // is cut short and the incoming track comes up to full volume.
+    state.overlap = None;
+
+    // The ramp advances with the track's play time, so a paused track has to
+    // play to fade out at all.
+    let _ = current.play();
+    let fade = FadeOut {
+        session: session.clone(),
+        steps: (length.as_millis() / TICK.as_millis()).max(1) as u32,
+        step: Arc::new(AtomicU32::new(0)),
+        volume: Arc::new(Mutex::new(None)),
+    };
+    // A track which ends on its own while fading still finishes the fade.
+    let added = current
+        .add_event(Event::Track(TrackEvent::End), fade.clone())
+        .and_then(|()| current.add_event(Event::Periodic(TICK, None), fade));
+    if added.is_err() {
+        return None;
+    }
+
+    state.fade = Some((current, end));
+    Some(end)
+}
+
+struct FadeOut<V> {
+    session: Arc<GuildSession<V>>,
+    steps: u32,
+    step: Arc<AtomicU32>,
+    /// The volume the track played at before fading.
+    volume: Arc<Mutex<Option<f32>>>,
+}
+
+// Derived, `Clone` would require `V: Clone`.
+impl<V> Clone for FadeOut<V> {
+    fn clone(&self) -> Self {
+        Self {
+            session: self.session.clone(),
+            steps: self.steps,
+            step: self.step.clone(),
+            volume: self.volume.clone(),
+        }
+    }
+}
+
+/// Skips or stops as asked while `track` faded out, unless that was done already.
+pub async fn finish_fade<V: Voice>(session: &GuildSession<V>, track: &TrackHandle) {
+    let end = {
+        let mut state = session.state.write().await;
+        match &state.fade {
+            Some((fading, _)) if fading.uuid() == track.uuid() => state.fade.take(),
+            _ => None,
+        }
+    };
+
+    if let Some((_, end)) = end {
+        session.end_fade(track, end).await;
+    }
+}
+
+#[async_trait]
+impl<V: Voice> VoiceEventHandler for FadeOut<V> {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let (state, track) = match ctx {
+            EventContext::Track(tracks) => *tracks.first()?,
+            _ => return None,
+        };
+
+        let step = if state.playing.is_done() {
+            self.steps
+        } else {
+            self.step.fetch_add(1, Ordering::Relaxed) + 1
+        };
+        if step >= self.steps {
+            finish_fade(&self.session, track).await;
+            return Some(Event::Cancel);
+        }
+
+        let volume = *self.volume.lock().await.get_or_insert(state.volume);
+        let _ = track.set_volume(volume * (1.0 - step as f32 / self.steps as f32));
+
+        None
+    }
+}
+
+#[command]
+#[aliases(xfade)]
+#[only_in(guilds)]
+async fn crossfade(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let settings = settings::settings(ctx).await;
+
+    if args.is_empty() {
+        let seconds = settings.get(guild_id).await.crossfade;
+        let reply = if seconds == 0 {
+            String::from("Crossfade is disabled")
+        } else {
+            format!("Crossfade is set to {}s", seconds)
+        };
+        check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+        return Ok(());
+    }
+    if !permissions::require_manager(ctx, msg).await {
+        return Ok(());
+    }
+
+    let seconds = match args.single::<u64>() {
+        Ok(seconds) if seconds <= MAX_CROSSFADE => seconds,
+        _ => {
+            check_msg(
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        format!("Usage: crossfade <0-{} seconds>", MAX_CROSSFADE),
+                    )
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    settings
+        .update(guild_id, |guild| guild.crossfade = seconds)
+        .await;
+
+    let reply = if seconds == 0 {
+        String::from("Crossfade disabled")
+    } else {
+        format!("Crossfade set to {}s", seconds)
+    };
+    check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+    Ok(())
+}
+
+#[command]
+#[aliases(buffer)]
+#[only_in(guilds)]
+async fn prebuffer(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let settings = settings::settings(ctx).await;
+
+    if args.is_empty() {
+        let seconds = settings
+            .get(guild_id)
+            .await
+            .prebuffer
+            .unwrap_or_else(default_prebuffer);
+        let reply = if seconds == 0 {
+            String::from("Pre-buffering is disabled")
+        } else {
+            format!("The next track is pre-buffered {}s before the end", seconds)
+        };
+        check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+        return Ok(());
+    }
+    if !permissions::require_manager(ctx, msg).await {
+        return Ok(());
+    }
+
+    let seconds = match args.single::<u64>() {
+        Ok(seconds) if seconds <= MAX_PREBUFFER => seconds,
+        _ => {
+            check_msg(
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        format!("Usage: prebuffer <0-{} seconds>", MAX_PREBUFFER),
+                    )
+                    .await,
+            );
+
+            return Ok(());
+        }
+    };
+
+    settings
+        .update(guild_id, |guild| guild.prebuffer = Some(seconds))
+        .await;
+
+    let reply = if seconds == 0 {
+        String::from("Pre-buffering disabled")
+    } else {
+        format!(
+            "The next track is now pre-buffered {}s before the end",
+            seconds
+        )
+    };
+    check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use std::{env, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+use serenity::{
+    cache::Cache, client::bridge::gateway::ShardManager, gateway::ConnectionStage, http::Http,
+    prelude::Mutex,
+};
+
+use crate::{
+    api::{self, Api},
+    dashboard::{self, Dashboard},
+    metrics::METRICS,
+    session::Sessions,
+    sources::Resolver,
+};
+
+/// Result message of a queue control.
+#[derive(Serialize)]
+pub struct Reply {
+    pub message: String,
+}
+
+/// What the HTTP handlers need from the running client.
+#[derive(Clone)]
+pub struct WebState {
+    pub cache: Arc<Cache>,
+    pub http: Arc<Http>,
+    pub sessions: Arc<Sessions>,
+    pub shard_manager: Arc<Mutex<ShardManager>>,
+    pub dashboard: Arc<Dashboard>,
+    pub api: Arc<Api>,
+    pub resolver: Arc<Resolver>,
+}
+
+/// Address of the embedded HTTP server, configured through `HTTP_ADDR`.
+/// The server is disabled when it is unset.
+pub fn addr() -> Option<SocketAddr> {
+    let addr = env::var("HTTP_ADDR").ok()?;
+    match addr.parse() {
+        Ok(addr) => Some(addr),
+        Err(why) => {
+            println!("Err parsing HTTP_ADDR {}: {:?}", addr, why);
+            None
+        }
+    }
+}
+
+/// Serves `/metrics`, `/healthz`, the dashboard and the control API until the
+/// process exits.
+pub async fn serve(addr: SocketAddr, state: WebState) {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .merge(dashboard::router())
+        .merge(api::router())
+        .with_state(state);
+
+    let server = match axum::Server::try_bind(&addr) {
+        Ok(server) => server,
+        Err(why) => {
+            println!("Err binding {}: {:?}", addr, why);
+            return;
+        }
+    };
+
+    println!("Serving HTTP on {}", addr);
+    if let Err(why) = server.serve(app.into_make_service()).await {
+        println!("Err serving HTTP: {:?}", why);
+    }
+}
+
+async fn metrics(State(state): State<WebState>) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS
+            .render(&state.cache, &state.sessions, &state.shard_manager)
+            .await,
+    )
+}
+
+/// Healthy while at least one shard is connected to the gateway.
+async fn healthz(State(state): State<WebState>) -> (StatusCode, &'static str) {
+    let manager = state.shard_manager.lock().await;
+    let runners = manager.runners.lock().await;
+
+    if runners
+        .values()
+        .any(|runner| runner.stage == ConnectionStage::Connected)
+    {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "disconnected")
+    }
+}
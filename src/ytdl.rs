@@ -0,0 +1,68 @@
+use rand::seq::SliceRandom;
+
+/// Extracts the YouTube video ID from the common watch, short link and shorts URLs.
+pub fn video_id(url: &str) -> Option<String> {
+    let id = if let Some((_, rest)) = url.split_once("youtu.be/") {
+        rest.split(['?', '&', '/']).next()
+    } else if let Some((_, rest)) = url.split_once("/shorts/") {
+        rest.split(['?', '&', '/']).next()
+    } else if url.contains("youtube.com") {
+        url.split_once('?')
+            .and_then(|(_, query)| query.split('&').find_map(|pair| pair.strip_prefix("v=")))
+    } else {
+        None
+    };
+
+    id.filter(|id| !id.is_empty()).map(String::from)
+}
+
+pub fn watch_url(video_id: &str) -> String {
+    format!("https://www.youtube.com/watch?v={video_id}")
+}
+
+/// Lists the video URLs of a playlist without extracting each video.
+pub async fn flat_playlist(url: &str) -> std::io::Result<Vec<String>> {
+    let output = tokio::process::Command::new("yt-dlp")
+        .args([
+            "--flat-playlist",
+            "--get-url",
+            "--compat-options",
+            "no-youtube-unavailable-videos",
+            url,
+        ])
+        .output()
+        .await?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split('\n')
+        .filter(|f| !f.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Picks a track related to `video_id` from its YouTube mix, skipping the given IDs.
+pub async fn related(video_id: &str, exclude: &[String]) -> Option<String> {
+    let output = tokio::process::Command::new("yt-dlp")
+        .args([
+            "--flat-playlist",
+            "--print",
+            "id",
+            "--playlist-end",
+            "25",
+            &format!("{}&list=RD{}", watch_url(video_id), video_id),
+        ])
+        .output()
+        .await
+        .ok()?;
+
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    let candidates: Vec<&str> = stdout
+        .lines()
+        .filter(|id| !id.is_empty() && *id != video_id && !exclude.iter().any(|e| e == id))
+        .take(5)
+        .collect();
+
+    candidates
+        .choose(&mut rand::thread_rng())
+        .map(|id| watch_url(id))
+}